@@ -0,0 +1,87 @@
+//! Compares the time spent by `World::hit`, which goes through a bounding
+//! volume hierarchy, with a linear scan over the same spheres.
+//!
+//! Run with `cargo bench --bench bvh`. Both find the same hits, which the
+//! unit tests of the hierarchy check.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::Instant;
+
+use parry3d::query::Ray;
+use rand::{Rng, SeedableRng};
+
+use photonr::material::{Lambertian, MaterialKind};
+use photonr::math::{Color, Point, Prng, Vector};
+use photonr::world::{Entity, Sphere, World, DEFAULT_T_MAX, DEFAULT_T_MIN};
+
+const NR_RAYS: usize = 20_000;
+
+fn random_spheres(rng: &mut Prng, count: usize) -> Vec<Arc<Sphere>> {
+    let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    (0..count)
+        .map(|_| {
+            let center = Point::new(
+                rng.gen_range(-50.0..50.0),
+                rng.gen_range(-50.0..50.0),
+                rng.gen_range(-50.0..50.0),
+            );
+            Arc::new(Sphere::new(center, 0.2, material.clone()))
+        })
+        .collect()
+}
+
+fn random_rays(rng: &mut Prng, count: usize) -> Vec<Ray> {
+    (0..count)
+        .map(|_| {
+            let dir = Vector::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            );
+            Ray::new(Point::new(0.0, 0.0, 0.0), dir)
+        })
+        .collect()
+}
+
+fn main() {
+    let mut rng = Prng::seed_from_u64(0);
+    let rays = random_rays(&mut rng, NR_RAYS);
+
+    println!(
+        "{:>8} {:>12} {:>12} {:>8}",
+        "spheres", "linear (ms)", "bvh (ms)", "speedup"
+    );
+    for count in [10, 100, 1_000, 10_000] {
+        let spheres = random_spheres(&mut rng, count);
+        let mut world = World::new();
+        for sphere in &spheres {
+            world.add(sphere.clone());
+        }
+
+        let start = Instant::now();
+        for ray in &rays {
+            let closest = spheres
+                .iter()
+                .filter_map(|sphere| sphere.hit(ray, DEFAULT_T_MIN, DEFAULT_T_MAX))
+                .min_by(|a, b| a.toi.total_cmp(&b.toi));
+            black_box(closest);
+        }
+        let linear = start.elapsed();
+
+        // The first query builds the hierarchy: include it in the timing
+        let start = Instant::now();
+        for ray in &rays {
+            black_box(world.hit(ray));
+        }
+        let bvh = start.elapsed();
+
+        println!(
+            "{:>8} {:>12.1} {:>12.1} {:>7.1}x",
+            count,
+            linear.as_secs_f64() * 1000.0,
+            bvh.as_secs_f64() * 1000.0,
+            linear.as_secs_f64() / bvh.as_secs_f64()
+        );
+    }
+}
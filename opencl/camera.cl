@@ -49,6 +49,31 @@ float4 lerp(float4 a, float4 b, float t) {
     return a + t * (b - a);
 }
 
+#define BACKGROUND_NONE 0
+#define BACKGROUND_SOLID 1
+#define BACKGROUND_GRADIENT 2
+
+struct background {
+    unsigned int kind;
+    unsigned int _dead0;
+    unsigned int _dead1;
+    unsigned int _dead2;
+    float4 top;
+    float4 bottom;
+};
+
+float4 background_color(const struct background *bg, float4 direction) {
+    if (bg->kind == BACKGROUND_SOLID) {
+        return bg->top;
+    }
+    if (bg->kind == BACKGROUND_GRADIENT) {
+        float4 unit_direction = normalize(direction);
+        float a = 0.5f * (unit_direction.y + 1.0f);
+        return lerp(bg->bottom, bg->top, a);
+    }
+    return (float4)(0.0f, 0.0f, 0.0f, 0.0f);
+}
+
 struct sphere {
     float4 center;
     float radius;
@@ -123,7 +148,22 @@ bool intersectRaySphere(const struct ray *r, __global const struct sphere *s, fl
     return true;
 }
 
-// TEMP
+struct plane {
+    float4 point;
+    // normalized
+    float4 normal;
+};
+
+bool intersectRayPlane(const struct ray *r, __global const struct plane *p, float *t) {
+    float d = dot(r->direction, p->normal);
+    if (fabs(d) < FLT_EPSILON) {
+        return false;
+    }
+    *t = dot(p->point - r->origin, p->normal) / d;
+    return *t >= 0;
+}
+
+// TEMP, indexed by the planes then the spheres
 float4 sphere_color(unsigned int i) {
     if (i == 0) {
         return (float4)(0.8f, 0.8f, 0.0f, 0.0f);
@@ -138,25 +178,37 @@ float4 sphere_color(unsigned int i) {
 
 float4 ray_color(struct ray r,
     float4 attenuation,
+    __global struct plane *planes,
+    unsigned int nr_planes,
     __global struct sphere *world,
     unsigned int nr_spheres,
+    const struct background *bg,
     unsigned int depth) {
 
     if (depth == 0) {
         return (float4)(0.0f, 1.0f, 0.0f, 0.0f);
     }
 
-    float background_gradient = 0.5f * (r.direction.y + 1.0f);
-    float4 white = (float4)(1.0f, 1.0f, 1.0f, 0.0f);
-    float4 red = (float4)(1.0f, 0.0f, 0.0f, 0.0f);
-    float4 blue = (float4)(0.5f, 0.7f, 1.0f, 0.0f);
-
     unsigned int i;
     float closest = 1000000.0f; // infinity
     bool hit = false;
     float4 normal;
     float4 pos;
     unsigned int hit_nr;
+    for (i = 0; i < nr_planes; i++) {
+        float t;
+        __global const struct plane *p = &planes[i];
+        if (intersectRayPlane(&r, p, &t)) {
+            hit = true;
+            if (t < closest) {
+                closest = t;
+                hit_nr = i;
+                pos = r.origin + t * r.direction;
+                // facing the ray
+                normal = dot(r.direction, p->normal) < 0 ? p->normal : -p->normal;
+            }
+        }
+    }
     for (i = 0; i < nr_spheres; i++) {
         float t;
         __global const struct sphere *s = &world[i];
@@ -164,7 +216,7 @@ float4 ray_color(struct ray r,
             hit = true;
             if (t < closest) {
                 closest = t;
-                hit_nr = i;
+                hit_nr = nr_planes + i;
                 pos = r.origin + t * r.direction;
                 normal = normalize(pos - s->center);
             }
@@ -172,8 +224,7 @@ float4 ray_color(struct ray r,
     }
 
     if (!hit) {
-        // No hit, let's have a nice background for now
-        return lerp(white, blue, background_gradient); 
+        return background_color(bg, r.direction);
     }
 
     // Simple Lambertian without rng
@@ -181,15 +232,18 @@ float4 ray_color(struct ray r,
     struct ray scattered;
     scattered.origin = pos;
     scattered.direction = normal;
-    return ray_color(scattered, attenuation, world, nr_spheres, depth - 1);
+    return ray_color(scattered, attenuation, planes, nr_planes, world, nr_spheres, bg, depth - 1);
 }
 
 // No RNG for now
 // No Recursion for now
 __kernel void trace(__global float4 *img,
+    __global struct plane *planes,
+    unsigned int nr_planes,
     __global struct sphere *spheres,
     struct camera cam,
-    unsigned int nr_spheres) {
+    unsigned int nr_spheres,
+    struct background bg) {
 
     int i = get_global_id(0);
     int j = get_global_id(1);
@@ -198,7 +252,7 @@ __kernel void trace(__global float4 *img,
     // ray
     struct ray r = get_ray(&cam, i, j);
     float4 attenuation = (float4)(1.0f, 1.0f, 1.0f, 1.0f);
-    float4 pixel_color = ray_color(r, attenuation, spheres, nr_spheres, 1); // cam.max_depth);
+    float4 pixel_color = ray_color(r, attenuation, planes, nr_planes, spheres, nr_spheres, &bg, 1); // cam.max_depth);
 
     unsigned int pos = i + cam.image_width * j;
     pos = sample + cam.samples_per_pixel * pos;
@@ -0,0 +1,314 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use parry3d::na;
+use serde::{Deserialize, Serialize};
+
+use crate::json;
+use crate::math::*;
+
+/// Color seen by the rays escaping the scene
+#[derive(Clone, Debug)]
+pub enum Background {
+    /// Same color in every direction
+    Solid { color: Color },
+    /// Vertical blend from `bottom` (looking down) to `top` (looking up)
+    Gradient { top: Color, bottom: Color },
+    /// Black: the scene is only lit by its emissive materials
+    None,
+    /// Image based lighting from an equirectangular map
+    Environment(EnvironmentMap),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Gradient {
+            top: Color::new(0.5, 0.7, 1.0),
+            bottom: Color::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Background {
+    /// Builds the background described by a scene, loading its environment
+    /// map if any
+    pub fn load(description: json::Background) -> Result<Self> {
+        Ok(match description {
+            json::Background::Solid { color } => Background::Solid { color },
+            json::Background::Gradient { top, bottom } => Background::Gradient { top, bottom },
+            json::Background::None => Background::None,
+            json::Background::Environment(description) => {
+                Background::Environment(EnvironmentMap::load(description)?)
+            }
+        })
+    }
+
+    pub fn color(&self, dir: &Vector) -> Color {
+        match self {
+            Background::Solid { color } => *color,
+            Background::Gradient { top, bottom } => {
+                let unit_direction = dir.normalize();
+                let a = 0.5 * (unit_direction.y + 1.0);
+                bottom.lerp(top, a)
+            }
+            Background::None => Color::new(0.0, 0.0, 0.0),
+            Background::Environment(map) => map.radiance(dir),
+        }
+    }
+
+    /// Importance samples a direction of the environment map, if any.
+    /// Returns the direction, its radiance and its solid angle density.
+    pub fn sample(&self, u1: Scalar, u2: Scalar) -> Option<(Vector, Color, Scalar)> {
+        match self {
+            Background::Environment(map) => map.sample(u1, u2),
+            _ => None,
+        }
+    }
+
+    /// Solid angle density of `sample` returning the direction `dir`
+    pub fn pdf(&self, dir: &Vector) -> Scalar {
+        match self {
+            Background::Environment(map) => map.pdf(dir),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Description of an environment map, as found in the scene file
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub struct EnvironmentDescription {
+    /// Equirectangular Radiance (.hdr) or OpenEXR (.exr) image
+    pub path: String,
+    /// Rotation around the vertical axis, in degrees
+    #[serde(default)]
+    pub rotation: Scalar,
+    /// Radiance scaling factor
+    #[serde(default = "default_intensity")]
+    pub intensity: Scalar,
+}
+
+fn default_intensity() -> Scalar {
+    1.0
+}
+
+/// Equirectangular environment map, loaded when the world is built.
+/// The center of the image is seen when looking down -Z, and its top row
+/// when looking up (+Y).
+#[derive(Clone)]
+pub struct EnvironmentMap {
+    description: EnvironmentDescription,
+    width: usize,
+    height: usize,
+    texels: Arc<Vec<Color>>,
+    /// Map space to world space
+    rotation: na::Rotation3<Scalar>,
+    /// Texels distribution, proportional to their luminance
+    distribution: Arc<Distribution2D>,
+}
+
+impl std::fmt::Debug for EnvironmentMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnvironmentMap")
+            .field("description", &self.description)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+fn luminance(c: &Color) -> Scalar {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+impl EnvironmentMap {
+    pub fn load(description: EnvironmentDescription) -> Result<Self> {
+        let image = image::open(&description.path)
+            .with_context(|| format!("Failed to load environment map '{}'", description.path))?
+            .into_rgb32f();
+        let width = image.width() as usize;
+        let height = image.height() as usize;
+        let texels: Vec<Color> = image
+            .pixels()
+            .map(|p| Color::new(p[0], p[1], p[2]) * description.intensity)
+            .collect();
+
+        // Weight each texel by the solid angle it covers, which shrinks
+        // towards the poles
+        let weights: Vec<Vec<Scalar>> = (0..height)
+            .map(|j| {
+                let sin_theta =
+                    Scalar::sin(std::f32::consts::PI * (j as Scalar + 0.5) / height as Scalar);
+                (0..width)
+                    .map(|i| luminance(&texels[i + j * width]) * sin_theta)
+                    .collect()
+            })
+            .collect();
+        let distribution = Distribution2D::new(&weights);
+
+        let rotation =
+            na::Rotation3::from_axis_angle(&Vector::y_axis(), description.rotation.to_radians());
+
+        Ok(EnvironmentMap {
+            description,
+            width,
+            height,
+            texels: Arc::new(texels),
+            rotation,
+            distribution: Arc::new(distribution),
+        })
+    }
+
+    /// Returns the (u, v) coordinates of a world space direction, in [0, 1]^2
+    fn direction_to_uv(&self, dir: &Vector) -> (Scalar, Scalar) {
+        let d = self.rotation.inverse_transform_vector(dir).normalize();
+        let phi = Scalar::atan2(d.x, -d.z);
+        let theta = Scalar::acos(d.y.clamp(-1.0, 1.0));
+        (
+            0.5 + phi / (2.0 * std::f32::consts::PI),
+            theta / std::f32::consts::PI,
+        )
+    }
+
+    fn uv_to_direction(&self, u: Scalar, v: Scalar) -> Vector {
+        let phi = (u - 0.5) * 2.0 * std::f32::consts::PI;
+        let theta = v * std::f32::consts::PI;
+        let d = Vector::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        );
+        self.rotation * d
+    }
+
+    fn texel(&self, u: Scalar, v: Scalar) -> Color {
+        let i = ((u * self.width as Scalar) as usize).min(self.width - 1);
+        let j = ((v * self.height as Scalar) as usize).min(self.height - 1);
+        self.texels[i + j * self.width]
+    }
+
+    /// Radiance coming from the direction `dir`
+    pub fn radiance(&self, dir: &Vector) -> Color {
+        let (u, v) = self.direction_to_uv(dir);
+        self.texel(u, v)
+    }
+
+    /// Importance samples a direction according to the map luminance.
+    /// Returns the direction, its radiance and its solid angle density.
+    pub fn sample(&self, u1: Scalar, u2: Scalar) -> Option<(Vector, Color, Scalar)> {
+        let ((u, v), map_pdf) = self.distribution.sample(u1, u2);
+        let sin_theta = Scalar::sin(v * std::f32::consts::PI);
+        if map_pdf == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+        let pdf = map_pdf / (2.0 * std::f32::consts::PI * std::f32::consts::PI * sin_theta);
+        Some((self.uv_to_direction(u, v), self.texel(u, v), pdf))
+    }
+
+    /// Solid angle density of `sample` returning the direction `dir`
+    pub fn pdf(&self, dir: &Vector) -> Scalar {
+        let (u, v) = self.direction_to_uv(dir);
+        let sin_theta = Scalar::sin(v * std::f32::consts::PI);
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v)
+            / (2.0 * std::f32::consts::PI * std::f32::consts::PI * sin_theta)
+    }
+}
+
+/// Piecewise constant 1D distribution over [0, 1)
+struct Distribution1D {
+    func: Vec<Scalar>,
+    cdf: Vec<Scalar>,
+    integral: Scalar,
+}
+
+impl Distribution1D {
+    fn new(func: &[Scalar]) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as Scalar;
+        }
+        let integral = cdf[n];
+        if integral == 0.0 {
+            // Degenerate case: fall back to a uniform distribution
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as Scalar / n as Scalar;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Distribution1D {
+            func: func.to_vec(),
+            cdf,
+            integral,
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Returns a value in [0, 1), its density and the index of its segment
+    fn sample(&self, u: Scalar) -> (Scalar, Scalar, usize) {
+        // Last cdf entry smaller than or equal to u
+        let offset = self
+            .cdf
+            .partition_point(|c| *c <= u)
+            .saturating_sub(1)
+            .min(self.count() - 1);
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+        let pdf = if self.integral > 0.0 {
+            self.func[offset] / self.integral
+        } else {
+            1.0
+        };
+        let x = ((offset as Scalar + du) / self.count() as Scalar).min(1.0 - Scalar::EPSILON);
+        (x, pdf, offset)
+    }
+
+    fn pdf(&self, x: Scalar) -> Scalar {
+        if self.integral == 0.0 {
+            return 1.0;
+        }
+        let offset = ((x * self.count() as Scalar) as usize).min(self.count() - 1);
+        self.func[offset] / self.integral
+    }
+}
+
+/// Piecewise constant 2D distribution over [0, 1)^2, built from rows of weights
+struct Distribution2D {
+    conditionals: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    fn new(rows: &[Vec<Scalar>]) -> Self {
+        let conditionals: Vec<_> = rows.iter().map(|row| Distribution1D::new(row)).collect();
+        let marginal_func: Vec<_> = conditionals.iter().map(|d| d.integral).collect();
+        Distribution2D {
+            conditionals,
+            marginal: Distribution1D::new(&marginal_func),
+        }
+    }
+
+    /// Returns a point (u, v) of [0, 1)^2 and its density
+    fn sample(&self, u1: Scalar, u2: Scalar) -> ((Scalar, Scalar), Scalar) {
+        let (v, pdf_v, row) = self.marginal.sample(u2);
+        let (u, pdf_u, _) = self.conditionals[row].sample(u1);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    fn pdf(&self, u: Scalar, v: Scalar) -> Scalar {
+        let row = ((v * self.marginal.count() as Scalar) as usize).min(self.marginal.count() - 1);
+        self.marginal.pdf(v) * self.conditionals[row].pdf(u)
+    }
+}
@@ -7,11 +7,11 @@ use clap::Parser;
 // https://github.com/cogciprocate/ocl/pull/168/files
 use ocl::ProQue;
 
-const ASPECT_RATIO: f32 = 16.0 / 9.0;
-const IMAGE_WIDTH: usize = 400;
-
+use photonr::background;
+use photonr::camera::{CameraSettings, Projection};
 use photonr::cli;
 use photonr::json;
+use photonr::math::{Point, Vector};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default, PartialEq)]
@@ -49,6 +49,16 @@ struct Sphere {
 
 unsafe impl ocl::OclPrm for Sphere {}
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct Plane {
+    point: Vec4,
+    // normalized
+    normal: Vec4,
+}
+
+unsafe impl ocl::OclPrm for Plane {}
+
 impl std::ops::Add for Vec4 {
     type Output = Vec4;
 
@@ -103,7 +113,7 @@ impl std::ops::Mul<f32> for Vec4 {
 impl From<Vec<f32>> for Vec4 {
     fn from(value: Vec<f32>) -> Self {
         let mut v = Vec4::zero();
-        if value.len() >= 1 {
+        if !value.is_empty() {
             v.x = value[0];
         }
         if value.len() >= 2 {
@@ -119,6 +129,18 @@ impl From<Vec<f32>> for Vec4 {
     }
 }
 
+impl From<Vector> for Vec4 {
+    fn from(value: Vector) -> Self {
+        Vec4::new(value.x, value.y, value.z, 0.0)
+    }
+}
+
+impl From<Point> for Vec4 {
+    fn from(value: Point) -> Self {
+        Vec4::new(value.x, value.y, value.z, 0.0)
+    }
+}
+
 impl From<json::Sphere> for Sphere {
     fn from(value: json::Sphere) -> Sphere {
         Sphere {
@@ -131,13 +153,99 @@ impl From<json::Sphere> for Sphere {
     }
 }
 
-fn mk_spheres(json: json::World) -> Vec<Sphere> {
-    json.shapes
-        .into_iter()
-        .map(|shape| match shape {
-            json::Shape::Sphere(s) => s.into(),
+impl From<json::Plane> for Plane {
+    fn from(value: json::Plane) -> Plane {
+        let normal = Vector::new(value.normal[0], value.normal[1], value.normal[2]);
+        Plane {
+            point: value.point.into(),
+            normal: normal.normalize().into(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+struct Background {
+    // 0: none, 1: solid (top color), 2: gradient
+    kind: u32,
+    _dead0: u32,
+    _dead1: u32,
+    _dead2: u32,
+    top: Vec4,
+    bottom: Vec4,
+}
+unsafe impl ocl::OclPrm for Background {}
+
+impl TryFrom<background::Background> for Background {
+    type Error = anyhow::Error;
+
+    fn try_from(value: background::Background) -> Result<Background> {
+        let (kind, top, bottom) = match value {
+            background::Background::None => (0, Vec4::zero(), Vec4::zero()),
+            background::Background::Solid { color } => (1, color.into(), Vec4::zero()),
+            background::Background::Gradient { top, bottom } => (2, top.into(), bottom.into()),
+            background::Background::Environment(_) => {
+                bail!("Environment maps are not supported on the GPU")
+            }
+        };
+        Ok(Background {
+            kind,
+            _dead0: 0,
+            _dead1: 0,
+            _dead2: 0,
+            top,
+            bottom,
         })
-        .collect()
+    }
+}
+
+/// Name of the shape, for the warnings
+fn shape_name(shape: &json::Shape) -> &'static str {
+    match shape {
+        json::Shape::Sphere(_) => "transformed sphere",
+        json::Shape::Triangle(_) => "triangle",
+        json::Shape::TriMesh(_) => "trimesh",
+        json::Shape::Cuboid(_) => "cuboid",
+        json::Shape::Cylinder(_) => "cylinder",
+        json::Shape::Cone(_) => "cone",
+        json::Shape::Capsule(_) => "capsule",
+        json::Shape::Plane(_) => "transformed plane",
+        json::Shape::Quad(_) => "quad",
+        json::Shape::Disk(_) => "disk",
+        json::Shape::Mesh(_) => "mesh",
+        json::Shape::Csg(_) => "csg",
+        json::Shape::Instance(_) => "instance",
+    }
+}
+
+fn mk_shapes(json: json::World) -> (Vec<Plane>, Vec<Sphere>) {
+    // Only planes and spheres, without transform, are supported on the GPU
+    // for now
+    let mut planes = Vec::new();
+    let mut spheres = Vec::new();
+    for shape in json.shapes {
+        match shape {
+            json::Shape::Plane(p) if p.transform.is_none() => planes.push(p.into()),
+            json::Shape::Sphere(s) if s.transform.is_none() => spheres.push(s.into()),
+            shape => eprintln!(
+                "Warning: skipping {}, not supported on the GPU",
+                shape_name(&shape)
+            ),
+        }
+    }
+    (planes, spheres)
+}
+
+/// Buffer holding `items`. OpenCL buffers can't be empty, a default item
+/// stands in for an empty slice.
+fn mk_buffer<T: ocl::OclPrm>(pro_que: &ProQue, items: &[T]) -> Result<ocl::Buffer<T>> {
+    let default = [T::default()];
+    let items = if items.is_empty() { &default } else { items };
+    Ok(pro_que
+        .buffer_builder()
+        .len(items.len())
+        .copy_host_slice(items)
+        .build()?)
 }
 
 #[repr(C)]
@@ -156,44 +264,23 @@ struct Camera {
 unsafe impl ocl::OclPrm for Camera {}
 
 impl Camera {
-    fn new(aspect_ratio: f32, image_width: u32, samples_per_pixel: u32, max_depth: u32) -> Camera {
-        let width = image_width as f32;
-        let mut height = width / aspect_ratio;
-        height = if height < 1.0 { 1.0 } else { height };
-        let image_height = height as u32;
-
-        let center = Vec4::zero();
-
-        // Determine viewport dimensions.
-        let focal_length = 1.0;
-        let viewport_height = 2.0;
-        let viewport_width = viewport_height * (width / height);
-
-        // Calculate the vectors across the horizontal and down the vertical viewport edges.
-        let viewport_u = Vec4::new(viewport_width, 0.0, 0.0, 0.0);
-        let viewport_v = Vec4::new(0.0, -viewport_height, 0.0, 0.0);
-
-        // Calculate the horizontal and vertical delta vectors from pixel to pixel.
-        let pixel_delta_u = viewport_u / width;
-        let pixel_delta_v = viewport_v / height;
-
-        // Calculate the location of the upper left pixel.
-        let viewport_upper_left: Vec4 = center
-            - Vec4::new(0.0, 0.0, focal_length, 0.0)
-            - (viewport_u / 2.0)
-            - (viewport_v / 2.0);
-        let pixel00_loc = viewport_upper_left + (pixel_delta_u + pixel_delta_v) * 0.5;
+    fn new(settings: &CameraSettings) -> Camera {
+        // Reuse the CPU camera computations so that both renderers frame the
+        // scene in the same way.
+        // TODO: no rng on the GPU side yet, so no defocus blur either
+        let image_height = settings.image_height() as u32;
+        let (center, pixel00_loc, pixel_delta_u, pixel_delta_v) = settings.viewport();
 
         Camera {
-            aspect_ratio,
-            image_width,
+            aspect_ratio: settings.aspect_ratio,
+            image_width: settings.image_width as u32,
             image_height,
-            center,
-            pixel00_loc,
-            pixel_delta_u,
-            pixel_delta_v,
-            samples_per_pixel,
-            max_depth,
+            center: center.into(),
+            pixel00_loc: pixel00_loc.into(),
+            pixel_delta_u: pixel_delta_u.into(),
+            pixel_delta_v: pixel_delta_v.into(),
+            samples_per_pixel: settings.samples_per_pixel as u32,
+            max_depth: settings.max_depth as u32,
         }
     }
 
@@ -239,24 +326,48 @@ fn linear_to_gamma(linear_component: f32) -> f32 {
 fn main() -> Result<()> {
     let cli = cli::Cli::parse();
 
-    let width = cli.width.unwrap_or(IMAGE_WIDTH) as u32;
-    let aspect_ratio = cli.aspect_ratio.unwrap_or(ASPECT_RATIO);
-    let samples_per_pixel = cli.samples_per_pixel.unwrap_or(10) as u32;
-    let max_depth = cli.max_depth.unwrap_or(10) as u32;
+    // TODO: support windows utf16 bullshit
+    let kernel = std::fs::read_to_string("./opencl/camera.cl")?;
+    if cli
+        .scene
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("gltf") || e.eq_ignore_ascii_case("glb"))
+    {
+        bail!("glTF scenes are not supported by the GPU renderer");
+    }
+    let scene_description = std::fs::read_to_string(&cli.scene)
+        .with_context(|| format!("Can't open scene '{}'", cli.scene.display()))?;
+
+    let jworld: json::World =
+        serde_json::from_str(&scene_description).context("Failed to read json input")?;
+
+    // Camera: defaults, then the scene description, then the command line
+    let mut settings = CameraSettings::default();
+    if let Some(camera) = &jworld.camera {
+        settings.apply_json(camera)?;
+    }
+    settings.apply_cli(&cli);
+
+    if settings.projection != Projection::Perspective {
+        bail!("Only the perspective projection is supported on the GPU");
+    }
 
-    let camera = Camera::new(aspect_ratio, width, samples_per_pixel, max_depth);
+    let camera = Camera::new(&settings);
 
     if cli.dump_info {
         camera.dump_info()
     }
 
-    // TODO: support windows utf16 bullshit
-    let kernel = std::fs::read_to_string("./opencl/camera.cl")?;
-    let scene_description = std::fs::read_to_string(r"./scene.json")?;
-
-    let jworld: json::World =
-        serde_json::from_str(&scene_description).context("Failed to read json input")?;
-    let spheres = mk_spheres(jworld);
+    // Checked before loading anything, environment maps can be huge
+    let background = match jworld.background.clone() {
+        Some(json::Background::Environment(_)) => {
+            bail!("Environment maps are not supported on the GPU")
+        }
+        Some(description) => background::Background::load(description)?,
+        None => background::Background::default(),
+    };
+    let background: Background = background.try_into()?;
+    let (planes, spheres) = mk_shapes(jworld);
 
     let pro_que = ProQue::builder()
         .src(kernel)
@@ -275,26 +386,30 @@ fn main() -> Result<()> {
         .buffer_builder()
         .len(camera.image_width * camera.image_height * camera.samples_per_pixel)
         .build()?;
+    let nr_planes: u32 = planes.len() as u32;
+    println!("create plane buffer");
+    for (i, plane) in planes.iter().enumerate() {
+        println!("plane {}: {:?} {:?}", i, plane.point, plane.normal);
+    }
+    let buf_planes = mk_buffer(&pro_que, &planes)?;
+
     let nr_spheres: u32 = spheres.len() as u32;
     println!("create sphere buffer");
-    println!("sphere 0: {:?} {}", spheres[0].center, spheres[0].radius);
-    println!("sphere 1: {:?} {}", spheres[1].center, spheres[1].radius);
-    println!("sphere 2: {:?} {}", spheres[2].center, spheres[2].radius);
-    println!("sphere 3: {:?} {}", spheres[3].center, spheres[3].radius);
-
-    let buf_spheres: ocl::Buffer<Sphere> = pro_que
-        .buffer_builder()
-        .len(nr_spheres)
-        .copy_host_slice(&spheres)
-        .build()?;
+    for (i, sphere) in spheres.iter().enumerate() {
+        println!("sphere {}: {:?} {}", i, sphere.center, sphere.radius);
+    }
+    let buf_spheres = mk_buffer(&pro_que, &spheres)?;
 
     println!("Setting kernel arguments");
     let kernel = pro_que
         .kernel_builder("trace")
         .arg(&img)
+        .arg(&buf_planes)
+        .arg(nr_planes)
         .arg(&buf_spheres)
-        .arg(&camera)
-        .arg(&nr_spheres)
+        .arg(camera)
+        .arg(nr_spheres)
+        .arg(background)
         .build()?;
 
     unsafe {
@@ -311,7 +426,7 @@ fn main() -> Result<()> {
 
     // for i in 80000..80100 {
     for i in 0..1 {
-        println!("{:?} {:?}", vec[10 * i + 0], vec[10 * i + 1]);
+        println!("{:?} {:?}", vec[10 * i], vec[10 * i + 1]);
         println!("{:?} {:?}", vec[10 * i + 2], vec[10 * i + 3]);
         println!("{:?} {:?}", vec[10 * i + 4], vec[10 * i + 5]);
         println!("{:?} {:?}", vec[10 * i + 6], vec[10 * i + 7]);
@@ -324,11 +439,7 @@ fn main() -> Result<()> {
     // println!("data: {:?}", &data[0..300]);
 
     // Save as PNG
-    let img = match image::RgbImage::from_vec(
-        camera.image_width as u32,
-        camera.image_height as u32,
-        data,
-    ) {
+    let img = match image::RgbImage::from_vec(camera.image_width, camera.image_height, data) {
         Some(img) => img,
         None => bail!("Failed to create RGB image"),
     };
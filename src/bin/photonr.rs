@@ -6,15 +6,11 @@ use anyhow::{bail, Context, Result};
 use clap::Parser;
 use encoding_rs::Encoding;
 
-const ASPECT_RATIO: f32 = 16.0 / 9.0;
-const IMAGE_WIDTH: usize = 400;
-
-use photonr::camera::Camera;
+use photonr::camera::{Camera, CameraSettings};
 use photonr::world::*;
-use photonr::{cli, json};
+use photonr::{cli, gltf, json};
 
 /// Helper function to deal with windows (utf16) vs other systems (utf8)
-
 fn detect_encoding(bytes: &[u8]) -> Option<String> {
     let (encoding, _) = Encoding::for_bom(bytes)?;
     eprintln!("Tentative encoding: {}", encoding.name());
@@ -24,21 +20,10 @@ fn detect_encoding(bytes: &[u8]) -> Option<String> {
     Some(content.to_string())
 }
 
-fn main() -> Result<()> {
-    let cli = cli::Cli::parse();
-
-    let width = cli.width.unwrap_or(IMAGE_WIDTH);
-    let aspect_ratio = cli.aspect_ratio.unwrap_or(ASPECT_RATIO);
-    let samples_per_pixel = cli.samples_per_pixel.unwrap_or(10);
-    let max_depth = cli.max_depth.unwrap_or(10);
-
-    let camera = Camera::new(aspect_ratio, width, samples_per_pixel, max_depth);
-
-    if cli.dump_info {
-        camera.dump_info()
-    }
-
-    let mut scene = File::open(r"./scene.json").context("Can't open default scene 'scene.json'")?;
+/// Reads a json scene description
+fn load_json(path: &Path) -> Result<(World, Option<json::Camera>)> {
+    let mut scene =
+        File::open(path).with_context(|| format!("Can't open scene '{}'", path.display()))?;
 
     // Read the file as bytes
     let mut buffer = Vec::new();
@@ -49,17 +34,18 @@ fn main() -> Result<()> {
     // Detect the encoding and load the content as a string
     let scene_description = match detect_encoding(&buffer) {
         Some(str) => str,
-        None => std::fs::read_to_string(r"./scene.json")?,
+        None => std::fs::read_to_string(path)?,
     };
 
-    let jworld: json::World =
+    let mut jworld: json::World =
         serde_json::from_str(&scene_description).context("Failed to read json input")?;
-    let world: World = jworld.into();
-
-    // Render
-    let data = camera.render(world);
+    let camera = jworld.camera.take();
+    let world = jworld.try_into().context("Invalid scene")?;
+    Ok((world, camera))
+}
 
-    // Save as PNG
+/// Saves the rendered image as a PNG
+fn save(camera: &Camera, data: Vec<u8>) -> Result<()> {
     let img = match image::RgbImage::from_vec(
         camera.image_width as u32,
         camera.image_height as u32,
@@ -71,7 +57,37 @@ fn main() -> Result<()> {
 
     let img = image::DynamicImage::ImageRgb8(img);
     let path = Path::new(r"./image.png");
-    img.save(path).context("Failed to save PNG image")?;
+    img.save(path).context("Failed to save PNG image")
+}
+
+fn main() -> Result<()> {
+    let cli = cli::Cli::parse();
+
+    let extension = cli.scene.extension().and_then(|e| e.to_str());
+    let (world, scene_camera) = match extension.map(|e| e.to_lowercase()).as_deref() {
+        Some("gltf" | "glb") => gltf::load(&cli.scene)?,
+        _ => load_json(&cli.scene)?,
+    };
+
+    // Camera: defaults, then the scene description, then the command line
+    let mut settings = CameraSettings::default();
+    if let Some(camera) = &scene_camera {
+        settings.apply_json(camera)?;
+    }
+    settings.apply_cli(&cli);
+
+    let camera = Camera::new(&settings);
+
+    if cli.dump_info {
+        camera.dump_info()
+    }
+
+    // Render, saving the image so far after every progressive pass
+    let data = camera.render(world, |data| {
+        if let Err(e) = save(&camera, data.to_vec()) {
+            eprintln!("Checkpoint failed: {:#}", e);
+        }
+    });
 
-    Ok(())
+    save(&camera, data)
 }
@@ -0,0 +1,313 @@
+use parry3d::bounding_volume::{Aabb, BoundingVolume};
+use parry3d::query::Ray;
+
+use crate::math::*;
+
+/// Maximum number of primitives in a leaf
+const MAX_LEAF_SIZE: usize = 4;
+/// Number of buckets used to evaluate the surface area heuristic
+const NR_BUCKETS: usize = 12;
+
+enum NodeKind {
+    /// Primitives `indices[first..first + count]`
+    Leaf { first: usize, count: usize },
+    /// The left child is the next node, the right one is at `right`
+    Interior { right: usize, axis: usize },
+}
+
+struct Node {
+    aabb: Aabb,
+    kind: NodeKind,
+}
+
+/// Bounding volume hierarchy over a set of primitives, built with the
+/// surface area heuristic. Nodes are stored in depth first order.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    /// Indices of the primitives, in the order of the leaves
+    indices: Vec<usize>,
+}
+
+fn surface_area(aabb: &Aabb) -> Scalar {
+    let e = aabb.extents();
+    2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+}
+
+fn merge_all<'a>(aabbs: impl Iterator<Item = &'a Aabb>) -> Aabb {
+    aabbs.fold(Aabb::new_invalid(), |acc, aabb| acc.merged(aabb))
+}
+
+impl Bvh {
+    /// Builds the hierarchy over the primitives whose bounding boxes are
+    /// `aabbs`. The primitives are then identified by their index in `aabbs`.
+    pub fn new(aabbs: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * aabbs.len()),
+            indices: (0..aabbs.len()).collect(),
+        };
+        if !aabbs.is_empty() {
+            let centroids: Vec<Point> = aabbs.iter().map(|aabb| aabb.center()).collect();
+            bvh.build(aabbs, &centroids, 0, aabbs.len());
+        }
+        bvh
+    }
+
+    fn build(&mut self, aabbs: &[Aabb], centroids: &[Point], start: usize, end: usize) -> usize {
+        let node_index = self.nodes.len();
+        let aabb = merge_all(self.indices[start..end].iter().map(|i| &aabbs[*i]));
+        let count = end - start;
+
+        let leaf = Node {
+            aabb,
+            kind: NodeKind::Leaf {
+                first: start,
+                count,
+            },
+        };
+        if count <= MAX_LEAF_SIZE {
+            self.nodes.push(leaf);
+            return node_index;
+        }
+
+        // Split along the largest extent of the centroids
+        let mut centroid_bounds = Aabb::new_invalid();
+        for i in &self.indices[start..end] {
+            centroid_bounds.take_point(centroids[*i]);
+        }
+        let extents = centroid_bounds.extents();
+        let axis = extents.imax();
+        if extents[axis] <= 0.0 {
+            // All the centroids are at the same place, no way to split them
+            self.nodes.push(leaf);
+            return node_index;
+        }
+
+        let bucket_of = |i: usize| -> usize {
+            let offset = (centroids[i][axis] - centroid_bounds.mins[axis]) / extents[axis];
+            ((offset * NR_BUCKETS as Scalar) as usize).min(NR_BUCKETS - 1)
+        };
+
+        let mut counts = [0usize; NR_BUCKETS];
+        let mut bounds = [Aabb::new_invalid(); NR_BUCKETS];
+        for i in &self.indices[start..end] {
+            let b = bucket_of(*i);
+            counts[b] += 1;
+            bounds[b].merge(&aabbs[*i]);
+        }
+
+        // Cost of splitting after each bucket, relative to the cost of a leaf
+        let mut best_cost = Scalar::INFINITY;
+        let mut best_split = 0;
+        for split in 0..NR_BUCKETS - 1 {
+            let left_count: usize = counts[..=split].iter().sum();
+            let right_count: usize = counts[split + 1..].iter().sum();
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let left = merge_all(bounds[..=split].iter());
+            let right = merge_all(bounds[split + 1..].iter());
+            let cost = 0.125
+                + (left_count as Scalar * surface_area(&left)
+                    + right_count as Scalar * surface_area(&right))
+                    / surface_area(&aabb);
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        if best_cost >= count as Scalar && count <= 2 * MAX_LEAF_SIZE {
+            self.nodes.push(leaf);
+            return node_index;
+        }
+
+        let mut mid = start
+            + partition(&mut self.indices[start..end], |i| {
+                bucket_of(*i) <= best_split
+            });
+        if mid == start || mid == end {
+            // Degenerate buckets, split in the middle
+            mid = (start + end) / 2;
+        }
+
+        self.nodes.push(Node {
+            aabb,
+            kind: NodeKind::Interior { right: 0, axis },
+        });
+        self.build(aabbs, centroids, start, mid);
+        let right_index = self.build(aabbs, centroids, mid, end);
+        if let NodeKind::Interior { right, .. } = &mut self.nodes[node_index].kind {
+            *right = right_index;
+        }
+        node_index
+    }
+
+    /// Visits the primitives whose bounding box is hit by `ray` before the
+    /// current closest hit. `hit` returns the time of impact of the ray on
+    /// the given primitive, if any, and the closest primitive is returned.
+    pub fn traverse<T>(
+        &self,
+        ray: &Ray,
+        max_toi: Scalar,
+        mut hit: impl FnMut(usize, Scalar) -> Option<(Scalar, T)>,
+    ) -> Option<(Scalar, T)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_dir = Vector::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z);
+        let mut closest: Option<(Scalar, T)> = None;
+        let mut closest_toi = max_toi;
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !intersects(&node.aabb, ray, &inv_dir, closest_toi) {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { first, count } => {
+                    for i in &self.indices[first..first + count] {
+                        if let Some((toi, value)) = hit(*i, closest_toi) {
+                            if toi < closest_toi {
+                                closest_toi = toi;
+                                closest = Some((toi, value));
+                            }
+                        }
+                    }
+                }
+                NodeKind::Interior { right, axis } => {
+                    // Visit the closest child first
+                    if ray.dir[axis] < 0.0 {
+                        stack.push(node_index + 1);
+                        stack.push(right);
+                    } else {
+                        stack.push(right);
+                        stack.push(node_index + 1);
+                    }
+                }
+            }
+        }
+        closest
+    }
+}
+
+/// Slab test: does `ray` go through `aabb` before `max_toi` ?
+fn intersects(aabb: &Aabb, ray: &Ray, inv_dir: &Vector, max_toi: Scalar) -> bool {
+    let mut t0: Scalar = 0.0;
+    let mut t1 = max_toi;
+    for axis in 0..3 {
+        let mut near = (aabb.mins[axis] - ray.origin[axis]) * inv_dir[axis];
+        let mut far = (aabb.maxs[axis] - ray.origin[axis]) * inv_dir[axis];
+        if near > far {
+            std::mem::swap(&mut near, &mut far);
+        }
+        // NaN (0 * inf) comparisons are false, which keeps the ray inside
+        if near > t0 {
+            t0 = near;
+        }
+        if far < t1 {
+            t1 = far;
+        }
+        if t0 > t1 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Moves the elements matching `pred` in front of the slice, and returns
+/// their number.
+fn partition<T>(slice: &mut [T], mut pred: impl FnMut(&T) -> bool) -> usize {
+    let mut first = 0;
+    for i in 0..slice.len() {
+        if pred(&slice[i]) {
+            slice.swap(first, i);
+            first += 1;
+        }
+    }
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::material::{Lambertian, MaterialKind};
+    use crate::world::{Entity, Sphere, DEFAULT_T_MAX, DEFAULT_T_MIN};
+
+    #[test]
+    fn traversal_finds_the_closest_hit() {
+        let mut rng = Prng::seed_from_u64(0);
+        let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let spheres: Vec<Sphere> = (0..200)
+            .map(|_| {
+                let center = Point::new(
+                    rng.gen_range(-20.0..20.0),
+                    rng.gen_range(-20.0..20.0),
+                    rng.gen_range(-20.0..20.0),
+                );
+                Sphere::new(center, rng.gen_range(0.1..2.0), material.clone())
+            })
+            .collect();
+        let aabbs: Vec<Aabb> = spheres.iter().map(|sphere| sphere.aabb()).collect();
+        let bvh = Bvh::new(&aabbs);
+
+        let mut rays: Vec<Ray> = (0..1000)
+            .map(|_| {
+                let origin = Point::new(
+                    rng.gen_range(-25.0..25.0),
+                    rng.gen_range(-25.0..25.0),
+                    rng.gen_range(-25.0..25.0),
+                );
+                let dir = Vector::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                );
+                Ray::new(origin, dir)
+            })
+            .collect();
+        // Rays parallel to the slabs
+        for axis in 0..3 {
+            for sign in [-1.0, 1.0] {
+                let mut dir = Vector::zeros();
+                dir[axis] = sign;
+                rays.push(Ray::new(Point::origin(), dir));
+                rays.push(Ray::new(spheres[axis].aabb().center(), dir));
+            }
+        }
+
+        let mut nr_hits = 0;
+        for ray in &rays {
+            let linear = spheres
+                .iter()
+                .enumerate()
+                .filter_map(|(i, sphere)| {
+                    sphere
+                        .hit(ray, DEFAULT_T_MIN, DEFAULT_T_MAX)
+                        .map(|hit| (hit.toi, i))
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            let traversed = bvh.traverse(ray, DEFAULT_T_MAX, |i, max_toi| {
+                spheres[i]
+                    .hit(ray, DEFAULT_T_MIN, max_toi)
+                    .map(|hit| (hit.toi, i))
+            });
+            assert_eq!(linear.map(|(_, i)| i), traversed.map(|(_, i)| i));
+            nr_hits += linear.is_some() as usize;
+        }
+        assert!(nr_hits > rays.len() / 10);
+    }
+
+    #[test]
+    fn empty_hierarchy() {
+        let bvh = Bvh::new(&[]);
+        let ray = Ray::new(Point::origin(), Vector::x());
+        assert!(bvh
+            .traverse(&ray, DEFAULT_T_MAX, |_, _| Some((0.0, ())))
+            .is_none());
+    }
+}
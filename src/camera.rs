@@ -1,21 +1,306 @@
+use anyhow::{Context, Result};
 use parry3d::query::Ray;
 use rand::Rng;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::io::Write;
 
-use crate::material::Material;
+use crate::cli::Cli;
+use crate::json;
+use crate::material::{BsdfFlags, Material, MaterialKind};
 use crate::math::*;
+use crate::photon::PhotonMap;
+use crate::sampler::Sampler;
 use crate::world::*;
 
+const ASPECT_RATIO: f32 = 16.0 / 9.0;
+const IMAGE_WIDTH: usize = 400;
+const SAMPLES_PER_PIXEL: usize = 10;
+const MAX_DEPTH: usize = 10;
+const PHOTONS: usize = 200_000;
+/// Initial gather radius of the progressive photon mapping, relative to the
+/// radius of the bounded entities
+const SPPM_RADIUS: Scalar = 0.01;
+/// Fraction of the new photons kept by each pass
+const SPPM_ALPHA: Scalar = 2.0 / 3.0;
+
+/// How rays are shot from the camera through the image plane
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Projection {
+    /// Pinhole camera
+    #[default]
+    Perspective,
+    /// Parallel rays, for technical and isometric renders
+    Orthographic,
+    /// Equidistant fisheye, vfov is the angle covered by the image height
+    Fisheye,
+    /// Full 360x180 degrees panorama, ignores vfov
+    Equirectangular,
+}
+
+/// How the light reaching the camera is computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Integrator {
+    /// Path tracing, sampling the lights at every diffuse bounce
+    #[default]
+    Path,
+    /// Path tracing, with the caustics estimated from a photon map instead
+    Photon,
+    /// Stochastic progressive photon mapping: every sample is a pass with a
+    /// new photon map, converging to the right answer
+    Sppm,
+}
+
+/// Everything needed to build a `Camera`.
+/// Shared by the CPU and the GPU renderers so that they frame a scene identically.
+#[derive(Debug, Clone)]
+pub struct CameraSettings {
+    pub aspect_ratio: f32,
+    pub image_width: usize,
+    pub samples_per_pixel: usize,
+    pub max_depth: usize,
+    /// Point the camera is looking from
+    pub lookfrom: Point,
+    /// Point the camera is looking at
+    pub lookat: Point,
+    /// Camera-relative "up" direction
+    pub vup: Vector,
+    /// Vertical field of view, in degrees
+    pub vfov: Scalar,
+    /// Variation angle of rays through each pixel, in degrees. 0 means no
+    /// defocus blur
+    pub defocus_angle: Scalar,
+    /// Distance from lookfrom to the plane of perfect focus. Defaults to the
+    /// distance between lookfrom and lookat
+    pub focus_dist: Option<Scalar>,
+    pub projection: Projection,
+    pub sampler: Sampler,
+    /// Seed of the random number generators. A given seed always produces
+    /// the same image
+    pub seed: u64,
+    pub integrator: Integrator,
+    /// Number of photons shot to build the caustics photon map, or the
+    /// photon map of every progressive pass
+    pub photons: usize,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        // A 90 degrees vertical fov at a distance of 1.0 gives the historical
+        // viewport height of 2.0
+        CameraSettings {
+            aspect_ratio: ASPECT_RATIO,
+            image_width: IMAGE_WIDTH,
+            samples_per_pixel: SAMPLES_PER_PIXEL,
+            max_depth: MAX_DEPTH,
+            lookfrom: Point::new(0.0, 0.0, 0.0),
+            lookat: Point::new(0.0, 0.0, -1.0),
+            vup: Vector::new(0.0, 1.0, 0.0),
+            vfov: 90.0,
+            defocus_angle: 0.0,
+            focus_dist: None,
+            projection: Projection::default(),
+            sampler: Sampler::default(),
+            seed: 0,
+            integrator: Integrator::default(),
+            photons: PHOTONS,
+        }
+    }
+}
+
+impl CameraSettings {
+    /// Override the settings with the values provided by the scene description
+    pub fn apply_json(&mut self, camera: &json::Camera) -> Result<()> {
+        if let Some(aspect_ratio) = camera.aspect_ratio {
+            self.aspect_ratio = aspect_ratio;
+        }
+        if let Some(width) = camera.width {
+            self.image_width = width;
+        }
+        if let Some(samples_per_pixel) = camera.samples_per_pixel {
+            self.samples_per_pixel = samples_per_pixel;
+        }
+        if let Some(max_depth) = camera.max_depth {
+            self.max_depth = max_depth;
+        }
+        if let Some(lookfrom) = &camera.lookfrom {
+            self.lookfrom = vec_to_point(lookfrom).context("Invalid camera lookfrom")?;
+        }
+        if let Some(lookat) = &camera.lookat {
+            self.lookat = vec_to_point(lookat).context("Invalid camera lookat")?;
+        }
+        if let Some(vup) = &camera.vup {
+            self.vup = vec_to_vector(vup).context("Invalid camera vup")?;
+        }
+        if let Some(vfov) = camera.vfov {
+            self.vfov = vfov;
+        }
+        if let Some(defocus_angle) = camera.defocus_angle {
+            self.defocus_angle = defocus_angle;
+        }
+        if let Some(focus_dist) = camera.focus_dist {
+            self.focus_dist = Some(focus_dist);
+        }
+        if let Some(projection) = camera.projection {
+            self.projection = projection;
+        }
+        if let Some(sampler) = camera.sampler {
+            self.sampler = sampler;
+        }
+        if let Some(seed) = camera.seed {
+            self.seed = seed;
+        }
+        if let Some(integrator) = camera.integrator {
+            self.integrator = integrator;
+        }
+        if let Some(photons) = camera.photons {
+            self.photons = photons;
+        }
+        Ok(())
+    }
+
+    /// Override the settings with the values provided on the command line
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(aspect_ratio) = cli.aspect_ratio {
+            self.aspect_ratio = aspect_ratio;
+        }
+        if let Some(width) = cli.width {
+            self.image_width = width;
+        }
+        if let Some(samples_per_pixel) = cli.samples_per_pixel {
+            self.samples_per_pixel = samples_per_pixel;
+        }
+        if let Some(max_depth) = cli.max_depth {
+            self.max_depth = max_depth;
+        }
+        if let Some(lookfrom) = cli.lookfrom {
+            self.lookfrom = lookfrom.into();
+        }
+        if let Some(lookat) = cli.lookat {
+            self.lookat = lookat.into();
+        }
+        if let Some(vup) = cli.vup {
+            self.vup = vup.into();
+        }
+        if let Some(vfov) = cli.vfov {
+            self.vfov = vfov;
+        }
+        if let Some(defocus_angle) = cli.defocus_angle {
+            self.defocus_angle = defocus_angle;
+        }
+        if let Some(focus_dist) = cli.focus_dist {
+            self.focus_dist = Some(focus_dist);
+        }
+        if let Some(projection) = cli.projection {
+            self.projection = projection;
+        }
+        if let Some(sampler) = cli.sampler {
+            self.sampler = sampler;
+        }
+        if let Some(seed) = cli.seed {
+            self.seed = seed;
+        }
+        if let Some(integrator) = cli.integrator {
+            self.integrator = integrator;
+        }
+        if let Some(photons) = cli.photons {
+            self.photons = photons;
+        }
+    }
+
+    /// Image height, in pixels, derived from the width and the aspect ratio.
+    pub fn image_height(&self) -> Scalar {
+        let height = self.image_width as Scalar / self.aspect_ratio;
+        if height < 1.0 {
+            1.0
+        } else {
+            height
+        }
+    }
+
+    /// Returns the camera orthonormal basis (u, v, w): u points to the
+    /// camera right, v to the camera up and w opposite to the view direction.
+    pub fn basis(&self) -> (Vector, Vector, Vector) {
+        let w = (self.lookfrom - self.lookat).normalize();
+        let u = self.vup.cross(&w).normalize();
+        let v = w.cross(&u);
+        (u, v, w)
+    }
+
+    /// Distance from lookfrom to the plane of perfect focus
+    pub fn focus_dist(&self) -> Scalar {
+        self.focus_dist
+            .unwrap_or_else(|| (self.lookfrom - self.lookat).norm())
+    }
+
+    /// Returns the defocus disk horizontal and vertical radius vectors
+    pub fn defocus_disk(&self) -> (Vector, Vector) {
+        let (u, v, _) = self.basis();
+        let defocus_radius =
+            self.focus_dist() * Scalar::tan((self.defocus_angle / 2.0).to_radians());
+        (u * defocus_radius, v * defocus_radius)
+    }
+
+    /// Returns (center, pixel00_loc, pixel_delta_u, pixel_delta_v), the
+    /// quantities needed to generate primary rays. The viewport lies in the
+    /// plane of perfect focus.
+    pub fn viewport(&self) -> (Point, Point, Vector, Vector) {
+        let width = self.image_width as Scalar;
+        let height = self.image_height();
+
+        let center = self.lookfrom;
+
+        // Determine viewport dimensions.
+        let focal_length = self.focus_dist();
+        let theta = self.vfov.to_radians();
+        let h = Scalar::tan(theta / 2.0);
+        let viewport_height = 2.0 * h * focal_length;
+        let viewport_width = viewport_height * (width / height);
+
+        let (u, v, w) = self.basis();
+
+        // Calculate the vectors across the horizontal and down the vertical viewport edges.
+        let viewport_u = viewport_width * u;
+        let viewport_v = viewport_height * -v;
+
+        // Calculate the horizontal and vertical delta vectors from pixel to pixel.
+        let pixel_delta_u = viewport_u / width;
+        let pixel_delta_v = viewport_v / height;
+
+        // Calculate the location of the upper left pixel.
+        let viewport_upper_left: Point =
+            center - focal_length * w - viewport_u / 2.0 - viewport_v / 2.0;
+        let pixel00_loc = viewport_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
+
+        (center, pixel00_loc, pixel_delta_u, pixel_delta_v)
+    }
+}
+
 #[derive(Debug)]
 pub struct Camera {
     aspect_ratio: f32,
     pub image_width: usize,
     pub image_height: usize,
+    projection: Projection,
+    sampler: Sampler,
+    seed: u64,
+    integrator: Integrator,
+    photons: usize,
     center: Point,
+    // camera frame basis vectors
+    u: Vector,
+    v: Vector,
+    w: Vector,
+    vfov: Scalar,
+    focus_dist: Scalar,
     pixel00_loc: Point,
     pixel_delta_u: Vector,
     pixel_delta_v: Vector,
+    defocus_angle: Scalar,
+    defocus_disk_u: Vector,
+    defocus_disk_v: Vector,
     samples_per_pixel: usize,
     max_depth: usize,
 }
@@ -41,26 +326,251 @@ fn write_color(c: &Color, samples_per_pixel: usize) -> Vec<u8> {
     ]
 }
 
-// - Make world background configurable
-fn ray_color(rng: &mut rand::rngs::ThreadRng, ray: &Ray, world: &World, depth: usize) -> Color {
+/// Weight of a sample drawn with the density `pdf`, when another strategy
+/// could have drawn it with the density `other_pdf` (Veach's power heuristic)
+fn power_heuristic(pdf: Scalar, other_pdf: Scalar) -> Scalar {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 {
+        return 0.0;
+    }
+    a / (a + b)
+}
+
+/// Light reflected towards `wo` at `point` straight from the light sources:
+/// the lights of the world, a point on an emissive entity and a direction of
+/// the environment map, connected to the point by shadow rays. The last two
+/// are weighted against the scattered rays that find them too.
+fn direct_light(
+    rng: &mut Prng,
+    point: &Point,
+    frame: &Frame,
+    wo: &Vector,
+    material: &MaterialKind,
+    world: &World,
+) -> Color {
+    let mut color = Color::zeros();
+    // Specular materials can't be lit from given directions
+    if !material.flags().is_non_specular() {
+        return color;
+    }
+
+    // Reflected light coming from the world space unit direction `wi`, with
+    // the BSDF and cosine factors, and the density of scattering towards it
+    let reflected = |wi: &Vector, radiance: &Color| {
+        let wi = frame.to_local(wi);
+        let f = material.eval(wo, &wi);
+        let contribution = f.component_mul(radiance) * wi.z.abs();
+        (contribution, material.pdf(wo, &wi))
+    };
+
+    for light in world.lights() {
+        let Some((wi, distance, radiance)) = light.sample(point) else {
+            continue;
+        };
+        let (contribution, _) = reflected(&wi, &radiance);
+        if contribution == Color::zeros() {
+            continue;
+        }
+        let shadow_ray = Ray::new(*point, wi);
+        if world
+            .hit(&shadow_ray)
+            .is_some_and(|hit| hit.intersection.toi < distance)
+        {
+            continue;
+        }
+        color += contribution;
+    }
+
+    if let Some((wi, radiance, pdf)) =
+        world.sample_emitter(point, rng.gen(), (rng.gen(), rng.gen()))
+    {
+        let (contribution, scatter_pdf) = reflected(&wi, &radiance);
+        color += contribution * power_heuristic(pdf, scatter_pdf) / pdf;
+    }
+
+    let background = world.background();
+    if let Some((wi, radiance, pdf)) = background.sample(rng.gen(), rng.gen()) {
+        let (contribution, scatter_pdf) = reflected(&wi, &radiance);
+        if contribution != Color::zeros() && world.hit(&Ray::new(*point, wi)).is_none() {
+            color += contribution * power_heuristic(pdf, scatter_pdf) / pdf;
+        }
+    }
+    color
+}
+
+/// Light emitted along `ray` by the entity it hits, or by the background if
+/// it hits nothing. `scatter_pdf` is the density with which the previous
+/// intersection scattered the ray, if it could have sampled a light source
+/// instead: the light is then weighted against `direct_light`.
+fn found_light(ray: &Ray, hit: Option<&Hit>, world: &World, scatter_pdf: Option<Scalar>) -> Color {
+    match hit {
+        Some(hit) => {
+            let weight =
+                scatter_pdf.map_or(1.0, |pdf| power_heuristic(pdf, world.emitter_pdf(ray, hit)));
+            hit.material.emitted(ray, &hit.intersection) * weight
+        }
+        None => {
+            let background = world.background();
+            let weight =
+                scatter_pdf.map_or(1.0, |pdf| power_heuristic(pdf, background.pdf(&ray.dir)));
+            background.color(&ray.dir) * weight
+        }
+    }
+}
+
+/// How a ray was scattered by the previous intersections
+#[derive(Debug, Clone, Copy, Default)]
+struct Bounce {
+    /// Density with which the previous intersection scattered the ray, if it
+    /// could have sampled a light source instead: light found by the ray is
+    /// then weighted against `direct_light`
+    scatter_pdf: Option<Scalar>,
+    /// The last non-specular intersection was diffuse
+    from_diffuse: bool,
+    /// The ray left a diffuse surface through specular ones only, the last
+    /// one bounded: light it finds makes a caustic on that surface, that the
+    /// caustics photon map covers. Photons can't be aimed at unbounded
+    /// entities, the light they focus is left to the scattered rays.
+    caustic: bool,
+}
+
+/// Radiance arriving along `ray`. With a `caustics` photon map, caustics
+/// are estimated from it at diffuse surfaces, and ignored when found by
+/// scattered rays.
+fn ray_color(
+    rng: &mut Prng,
+    ray: &Ray,
+    world: &World,
+    caustics: Option<&PhotonMap>,
+    depth: usize,
+    bounce: Bounce,
+) -> Color {
     if depth == 0 {
         return Color::new(0.0, 0.0, 0.0);
     }
+    let hit = world.hit(ray);
+    let emitted = if bounce.caustic && caustics.is_some() {
+        Color::zeros()
+    } else {
+        found_light(ray, hit.as_ref(), world, bounce.scatter_pdf)
+    };
+    let Some(hit) = hit else {
+        return emitted;
+    };
 
-    let background_gradient = 0.5 * (ray.dir.y + 1.0);
-    let white = Color::new(1.0, 1.0, 1.0);
-    let blue = Color::new(0.5, 0.7, 1.0);
+    let material = hit.material.as_ref();
+    let intersection = &hit.intersection;
 
-    if let Some((intersection, material)) = world.hit(ray) {
-        if let Some((attenuation, scattered)) = material.scatter(rng, ray, &intersection) {
-            let color = ray_color(rng, &scattered, world, depth - 1);
-            return attenuation.component_mul(&color);
+    let point = ray.point_at(intersection.toi);
+    let frame = Frame::new(&intersection.normal);
+    let wo = frame.to_local(&-ray.dir.normalize());
+    let mut direct = direct_light(rng, &point, &frame, &wo, material, world);
+    if let Some(caustics) = caustics {
+        if material.flags().intersects(BsdfFlags::DIFFUSE) {
+            direct += caustics.radiance(&point, &frame, &wo, material);
+        }
+    }
+
+    let Some(sample) = material.sample(&wo, (rng.gen(), rng.gen())) else {
+        return emitted + direct;
+    };
+    let scattered = Ray::new(point, frame.to_world(&sample.wi));
+    let specular = sample.flags.intersects(BsdfFlags::SPECULAR);
+    let bounce = Bounce {
+        scatter_pdf: (!specular).then_some(sample.pdf),
+        from_diffuse: if specular {
+            bounce.from_diffuse
         } else {
-            return Color::new(0.0, 0.0, 0.0);
+            sample.flags.intersects(BsdfFlags::DIFFUSE)
+        },
+        caustic: specular && bounce.from_diffuse && world.is_bounded(&hit),
+    };
+    let color = ray_color(rng, &scattered, world, caustics, depth - 1, bounce);
+    emitted + direct + sample.weight().component_mul(&color)
+}
+
+/// First diffuse surface seen through a pixel, where photons are gathered
+struct VisiblePoint {
+    point: Point,
+    frame: Frame,
+    wo: Vector,
+    material: MaterialKind,
+    /// Fraction of the light leaving the point that reaches the camera
+    throughput: Color,
+}
+
+/// Statistics of a pixel, accumulated over the progressive passes
+struct SppmPixel {
+    /// Light found by the camera paths
+    direct: Color,
+    /// Gather radius
+    radius: Scalar,
+    /// Number of photons gathered, reduced as the radius shrinks
+    photons: Scalar,
+    /// Power of these photons reaching the camera
+    flux: Color,
+}
+
+/// Follows `ray` through specular and glossy surfaces up to a diffuse one.
+/// Returns the light found on the way, including the direct lighting of the
+/// diffuse surface, and that surface if any.
+fn visible_point(
+    rng: &mut Prng,
+    ray: &Ray,
+    world: &World,
+    max_depth: usize,
+) -> (Color, Option<VisiblePoint>) {
+    let mut ray = *ray;
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+    let mut color = Color::zeros();
+    let mut scatter_pdf: Option<Scalar> = None;
+    for _ in 0..max_depth {
+        let hit = world.hit(&ray);
+        color += throughput.component_mul(&found_light(&ray, hit.as_ref(), world, scatter_pdf));
+        let Some(hit) = hit else {
+            break;
+        };
+
+        let material = hit.material.as_ref();
+
+        let point = ray.point_at(hit.intersection.toi);
+        let frame = Frame::new(&hit.intersection.normal);
+        let wo = frame.to_local(&-ray.dir.normalize());
+        let direct = direct_light(rng, &point, &frame, &wo, material, world);
+        color += throughput.component_mul(&direct);
+
+        if material.flags().intersects(BsdfFlags::DIFFUSE) {
+            // Photons only bring the light of the further bounces: the light
+            // sources found by scattering complete the direct lighting
+            if let Some(sample) = material.sample(&wo, (rng.gen(), rng.gen())) {
+                let scattered = Ray::new(point, frame.to_world(&sample.wi));
+                let scatter_pdf =
+                    (!sample.flags.intersects(BsdfFlags::SPECULAR)).then_some(sample.pdf);
+                let found = found_light(
+                    &scattered,
+                    world.hit(&scattered).as_ref(),
+                    world,
+                    scatter_pdf,
+                );
+                color += throughput.component_mul(&sample.weight().component_mul(&found));
+            }
+            let visible = VisiblePoint {
+                point,
+                frame,
+                wo,
+                material: hit.material.into_owned(),
+                throughput,
+            };
+            return (color, Some(visible));
         }
+        let Some(sample) = material.sample(&wo, (rng.gen(), rng.gen())) else {
+            break;
+        };
+        throughput = throughput.component_mul(&sample.weight());
+        scatter_pdf = (!sample.flags.intersects(BsdfFlags::SPECULAR)).then_some(sample.pdf);
+        ray = Ray::new(point, frame.to_world(&sample.wi));
     }
-    // No hit, let's have a nice background for now
-    white.lerp(&blue, background_gradient)
+    (color, None)
 }
 
 impl Camera {
@@ -68,76 +578,142 @@ impl Camera {
         println!("image width: {}", self.image_width);
         println!("image height: {}", self.image_height);
         println!("aspect ratio: {}", self.aspect_ratio);
+        println!("projection: {:?}", self.projection);
+        println!("center: {}", self.center);
         println!("samples per pixel: {}", self.samples_per_pixel);
+        println!("sampler: {:?}", self.sampler);
+        println!("seed: {}", self.seed);
+        println!("integrator: {:?}", self.integrator);
+        if self.integrator != Integrator::Path {
+            println!("photons: {}", self.photons);
+        }
         println!("max depth: {}", self.max_depth);
+        println!("defocus angle: {}", self.defocus_angle);
     }
 
-    fn pixel_sample_square(&self, rng: &mut rand::rngs::ThreadRng) -> Vector {
-        // Returns a random point in the square surrounding a pixel at the origin.
-        let offset: f32 = rng.gen_range(0.0..1.0);
-
-        let px = -0.5 + offset;
-        let py = -0.5 + offset;
-        (px * self.pixel_delta_u) + (py * self.pixel_delta_v)
+    fn defocus_disk_sample(&self, rng: &mut Prng, pixel_seed: u64, sample: usize) -> Vector {
+        // Returns a random offset in the camera defocus disk.
+        let (u, v) = self
+            .sampler
+            .sample_2d(rng, pixel_seed, sample, self.samples_per_pixel, 1);
+        let p = concentric_sample_disk(u, v);
+        (p.x * self.defocus_disk_u) + (p.y * self.defocus_disk_v)
     }
 
-    pub fn new(
-        aspect_ratio: f32,
-        image_width: usize,
-        samples_per_pixel: usize,
-        max_depth: usize,
-    ) -> Camera {
-        let width = image_width as f32;
-        let mut height = width / aspect_ratio;
-        height = if height < 1.0 { 1.0 } else { height };
-        let image_height = height as usize;
-
-        let center = Point::new(0.0, 0.0, 0.0);
-
-        // Determine viewport dimensions.
-        let focal_length = 1.0;
-        let viewport_height = 2.0;
-        let viewport_width = viewport_height * (width / height);
-
-        // Calculate the vectors across the horizontal and down the vertical viewport edges.
-        let viewport_u = Vector::new(viewport_width, 0.0, 0.0);
-        let viewport_v = Vector::new(0.0, -viewport_height, 0.0);
+    fn pixel_sample_square(
+        &self,
+        rng: &mut Prng,
+        pixel_seed: u64,
+        sample: usize,
+    ) -> (Scalar, Scalar) {
+        // Returns a random point in the square surrounding a pixel at the origin.
+        let (u, v) = self
+            .sampler
+            .sample_2d(rng, pixel_seed, sample, self.samples_per_pixel, 0);
 
-        // Calculate the horizontal and vertical delta vectors from pixel to pixel.
-        let pixel_delta_u = viewport_u / width;
-        let pixel_delta_v = viewport_v / height;
+        let px = -0.5 + u;
+        let py = -0.5 + v;
+        (px, py)
+    }
 
-        // Calculate the location of the upper left pixel.
-        let viewport_upper_left: Point =
-            (center - Point::new(0.0, 0.0, focal_length) - viewport_u / 2.0 - viewport_v / 2.0)
-                .into();
-        let pixel00_loc = viewport_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
+    pub fn new(settings: &CameraSettings) -> Camera {
+        let image_height = settings.image_height() as usize;
+        let (center, pixel00_loc, pixel_delta_u, pixel_delta_v) = settings.viewport();
+        let (defocus_disk_u, defocus_disk_v) = settings.defocus_disk();
+        let (u, v, w) = settings.basis();
 
         Camera {
-            aspect_ratio,
-            image_width,
+            aspect_ratio: settings.aspect_ratio,
+            image_width: settings.image_width,
             image_height,
+            projection: settings.projection,
+            sampler: settings.sampler,
+            seed: settings.seed,
+            integrator: settings.integrator,
+            photons: settings.photons,
             center,
+            u,
+            v,
+            w,
+            vfov: settings.vfov.to_radians(),
+            focus_dist: settings.focus_dist(),
             pixel00_loc,
             pixel_delta_u,
             pixel_delta_v,
-            samples_per_pixel,
-            max_depth,
+            defocus_angle: settings.defocus_angle,
+            defocus_disk_u,
+            defocus_disk_v,
+            samples_per_pixel: settings.samples_per_pixel,
+            max_depth: settings.max_depth,
         }
     }
 
-    fn get_ray(&self, rng: &mut rand::rngs::ThreadRng, i: usize, j: usize) -> Ray {
-        let pixel_center: Point = self.pixel00_loc
-            + (i as Scalar * self.pixel_delta_u)
-            + (j as Scalar * self.pixel_delta_v);
+    /// Returns the origin and direction of the ray going through the image
+    /// position (x, y), in pixels. The direction is scaled such that
+    /// origin + direction lies in the plane of perfect focus.
+    fn project(&self, x: Scalar, y: Scalar) -> (Point, Vector) {
+        let width = self.image_width as Scalar;
+        let height = self.image_height as Scalar;
+        match self.projection {
+            Projection::Perspective => {
+                let pixel_sample: Point = self.pixel00_loc
+                    + ((x - 0.5) * self.pixel_delta_u)
+                    + ((y - 0.5) * self.pixel_delta_v);
+                (self.center, pixel_sample - self.center)
+            }
+            Projection::Orthographic => {
+                // Same viewport as the perspective projection, but every ray
+                // is parallel to the view direction
+                let pixel_sample: Point = self.pixel00_loc
+                    + ((x - 0.5) * self.pixel_delta_u)
+                    + ((y - 0.5) * self.pixel_delta_v);
+                let direction = -self.focus_dist * self.w;
+                (pixel_sample - direction, direction)
+            }
+            Projection::Fisheye => {
+                // Normalized coordinates, the image height spans [-1, 1]
+                let sx = (2.0 * x / width - 1.0) * width / height;
+                let sy = 1.0 - 2.0 * y / height;
+                let theta = (sx * sx + sy * sy).sqrt() * self.vfov / 2.0;
+                let phi = sy.atan2(sx);
+                let direction = theta.sin() * phi.cos() * self.u + theta.sin() * phi.sin() * self.v
+                    - theta.cos() * self.w;
+                (self.center, self.focus_dist * direction)
+            }
+            Projection::Equirectangular => {
+                let longitude = (x / width - 0.5) * 2.0 * std::f32::consts::PI;
+                let latitude = (0.5 - y / height) * std::f32::consts::PI;
+                let direction = latitude.cos() * longitude.sin() * self.u + latitude.sin() * self.v
+                    - latitude.cos() * longitude.cos() * self.w;
+                (self.center, self.focus_dist * direction)
+            }
+        }
+    }
+
+    /// Camera ray through the pixel (i, j). Its direction is unit length, so
+    /// that the hit range of the world is a distance on every ray.
+    fn get_ray(&self, rng: &mut Prng, i: usize, j: usize, sample: usize) -> Ray {
+        let pixel_seed = hash(self.seed, (i + j * self.image_width) as u64);
+        let (px, py) = self.pixel_sample_square(rng, pixel_seed, sample);
+        let x = i as Scalar + 0.5 + px;
+        let y = j as Scalar + 0.5 + py;
 
-        let pixel_sample: Point = pixel_center + self.pixel_sample_square(rng);
+        let (origin, direction) = self.project(x, y);
 
-        let ray_direction: Vector = pixel_sample - self.center;
-        Ray::new(self.center, ray_direction)
+        if self.defocus_angle <= 0.0 {
+            return Ray::new(origin, direction.normalize());
+        }
+
+        // Thin lens: move the origin on the defocus disk, but keep aiming at
+        // the same point in the focus plane
+        let focus_point = origin + direction;
+        let ray_origin = origin + self.defocus_disk_sample(rng, pixel_seed, sample);
+        Ray::new(ray_origin, (focus_point - ray_origin).normalize())
     }
 
-    pub fn render(&self, world: World) -> Vec<u8> {
+    /// Renders `world`. Progressive integrators call `checkpoint` with the
+    /// image after every pass but the last one.
+    pub fn render(&self, world: World, checkpoint: impl FnMut(&[u8])) -> Vec<u8> {
         println!(
             "Generating image: size {} x {}",
             self.image_width, self.image_height
@@ -146,6 +722,36 @@ impl Camera {
         // very simple time computation
         let start = std::time::Instant::now();
 
+        let data = match self.integrator {
+            Integrator::Path | Integrator::Photon => self.render_paths(&world),
+            Integrator::Sppm => self.render_progressive(&world, checkpoint),
+        };
+        println!("\r                                          ");
+        std::io::stdout().flush().unwrap();
+
+        let duration = start.elapsed();
+        println!("\rDone in {} milliseconds", duration.as_millis());
+        data
+    }
+
+    /// Traces `samples_per_pixel` paths through every pixel
+    fn render_paths(&self, world: &World) -> Vec<u8> {
+        let start = std::time::Instant::now();
+        let caustics = match self.integrator {
+            Integrator::Path | Integrator::Sppm => None,
+            Integrator::Photon => {
+                let map = PhotonMap::caustics(world, self.photons, self.max_depth, self.seed);
+                if let Some(map) = &map {
+                    println!(
+                        "Caustics photon map: {} photons stored in {} milliseconds",
+                        map.len(),
+                        start.elapsed().as_millis()
+                    );
+                }
+                map
+            }
+        };
+
         let cnt = std::sync::Arc::new(std::sync::Mutex::new(0));
         let data = (0..self.image_height)
             .into_par_iter()
@@ -156,15 +762,23 @@ impl Camera {
                     let ratio = val * 100 / self.image_height as u32;
                     print!("\rCurrent progress: {} %", ratio);
                 }
-                let mut rng = rand::thread_rng();
 
                 std::io::stdout().flush().unwrap();
                 let res = (0..self.image_width)
                     .map(|i| {
                         let mut pixel_color = Color::new(0.0, 0.0, 0.0);
-                        for _sample in 0..self.samples_per_pixel {
-                            let ray = self.get_ray(&mut rng, i, j);
-                            pixel_color += ray_color(&mut rng, &ray, &world, self.max_depth);
+                        let pixel = (i + j * self.image_width) as u64;
+                        for sample in 0..self.samples_per_pixel {
+                            let mut rng = sample_rng(self.seed, pixel, sample as u64);
+                            let ray = self.get_ray(&mut rng, i, j, sample);
+                            pixel_color += ray_color(
+                                &mut rng,
+                                &ray,
+                                world,
+                                caustics.as_ref(),
+                                self.max_depth,
+                                Bounce::default(),
+                            );
                         }
                         write_color(&pixel_color, self.samples_per_pixel)
                     })
@@ -174,12 +788,137 @@ impl Camera {
                 res
             })
             .collect::<Vec<_>>();
-        println!("\r                                          ");
-        std::io::stdout().flush().unwrap();
-
-        let duration = start.elapsed();
-        println!("\rDone in {} milliseconds", duration.as_millis());
         let data: Vec<_> = data.into_iter().flatten().collect();
         data.into_iter().flatten().collect()
     }
+
+    /// Stochastic progressive photon mapping (Hachisuka and Jensen): every
+    /// pass traces a path through each pixel up to a diffuse surface, and
+    /// gathers the photons of a new photon map around it, within a radius
+    /// that shrinks as photons accumulate. There are `samples_per_pixel`
+    /// passes.
+    fn render_progressive(&self, world: &World, mut checkpoint: impl FnMut(&[u8])) -> Vec<u8> {
+        let radius = SPPM_RADIUS
+            * world
+                .bounds()
+                .map_or(1.0, |aabb| aabb.half_extents().norm());
+        let mut pixels: Vec<_> = (0..self.image_width * self.image_height)
+            .map(|_| SppmPixel {
+                direct: Color::zeros(),
+                radius,
+                photons: 0.0,
+                flux: Color::zeros(),
+            })
+            .collect();
+
+        let passes = self.samples_per_pixel;
+        let mut data = Vec::new();
+        for pass in 0..passes {
+            print!("\rPass {} / {}", pass + 1, passes);
+            std::io::stdout().flush().unwrap();
+
+            let seed = hash(self.seed, pass as u64);
+            let photons = PhotonMap::global(world, self.photons, self.max_depth, seed);
+            if photons.is_none() && pass == 0 {
+                eprintln!(
+                    "\nWarning: no light reaches the scene, only direct lighting is rendered"
+                );
+            }
+            pixels
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(index, pixel)| {
+                    let (i, j) = (index % self.image_width, index / self.image_width);
+                    let mut rng = sample_rng(self.seed, index as u64, pass as u64);
+                    let ray = self.get_ray(&mut rng, i, j, pass);
+                    let (color, visible) = visible_point(&mut rng, &ray, world, self.max_depth);
+                    pixel.direct += color;
+                    let (Some(visible), Some(photons)) = (visible, &photons) else {
+                        return;
+                    };
+                    let (flux, count) = photons.gather(
+                        &visible.point,
+                        pixel.radius,
+                        &visible.frame,
+                        &visible.wo,
+                        &visible.material,
+                    );
+                    if count == 0 {
+                        return;
+                    }
+                    // Only a fraction of the new photons is kept, and the radius
+                    // shrinks so that their density stays the same
+                    let photons = pixel.photons + SPPM_ALPHA * count as Scalar;
+                    let ratio = photons / (pixel.photons + count as Scalar);
+                    pixel.flux = (pixel.flux + visible.throughput.component_mul(&flux)) * ratio;
+                    pixel.radius *= ratio.sqrt();
+                    pixel.photons = photons;
+                });
+
+            let done = (pass + 1) as Scalar;
+            data = pixels
+                .iter()
+                .flat_map(|pixel| {
+                    let area = std::f32::consts::PI * pixel.radius * pixel.radius;
+                    let color = (pixel.direct + pixel.flux / area) / done;
+                    write_color(&color, 1)
+                })
+                .collect();
+            if pass + 1 < passes {
+                checkpoint(&data);
+            }
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::material::Lambertian;
+
+    /// Whatever the projection, and with or without defocus blur, `t_max`
+    /// clips the camera rays at the same distance
+    #[test]
+    fn t_max_is_a_distance() {
+        let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let mut world = World::new();
+        // Around every ray origin
+        world.add(Arc::new(Sphere::new(Point::origin(), 20.0, material)));
+
+        let projections = [
+            Projection::Perspective,
+            Projection::Orthographic,
+            Projection::Fisheye,
+            Projection::Equirectangular,
+        ];
+        let mut rng = Prng::seed_from_u64(0);
+        for projection in projections {
+            for defocus_angle in [0.0, 10.0] {
+                let settings = CameraSettings {
+                    image_width: 16,
+                    lookat: Point::new(0.0, 0.0, -3.0),
+                    defocus_angle,
+                    projection,
+                    ..CameraSettings::default()
+                };
+                let camera = Camera::new(&settings);
+                for j in 0..camera.image_height {
+                    for i in 0..camera.image_width {
+                        let ray = camera.get_ray(&mut rng, i, j, 0);
+                        world.set_t_range(0.001, Scalar::INFINITY);
+                        let hit = world.hit(&ray).unwrap();
+                        let distance = (ray.point_at(hit.intersection.toi) - ray.origin).norm();
+                        assert!((distance - hit.intersection.toi).abs() < 1e-4);
+                        world.set_t_range(0.001, 0.99 * distance);
+                        assert!(world.hit(&ray).is_none());
+                    }
+                }
+            }
+        }
+    }
 }
@@ -1,11 +1,35 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
+use crate::camera::{Integrator, Projection};
+use crate::sampler::Sampler;
+
+/// Parses a "x,y,z" command line argument
+fn parse_triplet(arg: &str) -> Result<[f32; 3], String> {
+    let values = arg
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().map_err(|e| format!("{}: {}", v, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [x, y, z] => Ok([x, y, z]),
+        _ => Err(format!(
+            "expected 3 comma separated values, got {}",
+            values.len()
+        )),
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(
     help_template = "{author-with-newline}{name} {version} {about-section}\n {usage-heading} {usage} \n {all-args} {tab}"
 )]
 pub struct Cli {
+    #[arg(long, value_name = "FILE", default_value = "scene.json")]
+    /// scene to render: a json description, or a .gltf/.glb file
+    pub scene: PathBuf,
+
     #[arg(short, long, value_name = "N")]
     /// number of rays per pixel. Default is 10
     pub samples_per_pixel: Option<usize>,
@@ -22,6 +46,50 @@ pub struct Cli {
     /// Max number of generated secondary rays. Default is 10
     pub max_depth: Option<usize>,
 
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_triplet, allow_hyphen_values = true)]
+    /// point the camera is looking from. Default is 0,0,0
+    pub lookfrom: Option<[f32; 3]>,
+
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_triplet, allow_hyphen_values = true)]
+    /// point the camera is looking at. Default is 0,0,-1
+    pub lookat: Option<[f32; 3]>,
+
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_triplet, allow_hyphen_values = true)]
+    /// camera-relative up direction. Default is 0,1,0
+    pub vup: Option<[f32; 3]>,
+
+    #[arg(long, value_name = "DEG")]
+    /// vertical field of view, in degrees. Default is 90
+    pub vfov: Option<f32>,
+
+    #[arg(long, value_name = "DEG")]
+    /// defocus blur angle, in degrees. Default is 0 (no blur)
+    pub defocus_angle: Option<f32>,
+
+    #[arg(long, value_name = "DIST")]
+    /// distance to the plane of perfect focus. Default is the lookfrom/lookat distance
+    pub focus_dist: Option<f32>,
+
+    #[arg(long, value_enum)]
+    /// camera projection. Default is perspective
+    pub projection: Option<Projection>,
+
+    #[arg(long, value_enum)]
+    /// pixel sampling strategy. Default is random
+    pub sampler: Option<Sampler>,
+
+    #[arg(long, value_enum)]
+    /// light transport algorithm. Default is path
+    pub integrator: Option<Integrator>,
+
+    #[arg(long, value_name = "COUNT")]
+    /// number of photons shot by the photon integrator, or by every sppm pass. Default is 200000
+    pub photons: Option<usize>,
+
+    #[arg(long, value_name = "SEED")]
+    /// seed of the random number generators. Default is 0
+    pub seed: Option<u64>,
+
     #[arg(short, long)]
     /// Display camera information
     pub dump_info: bool,
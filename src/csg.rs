@@ -0,0 +1,193 @@
+use parry3d::bounding_volume::{Aabb, BoundingVolume};
+use parry3d::query::{Ray, RayIntersection};
+use serde::{Deserialize, Serialize};
+
+use crate::material::*;
+use crate::math::*;
+use crate::world::{Entity, SharedEntity};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    /// Inside any of the children
+    Union,
+    /// Inside all of the children
+    Intersection,
+    /// Inside the first child, but outside all the others
+    Difference,
+}
+
+impl Operation {
+    fn inside(&self, children: &[bool]) -> bool {
+        match self {
+            Operation::Union => children.iter().any(|inside| *inside),
+            Operation::Intersection => children.iter().all(|inside| *inside),
+            Operation::Difference => children[0] && !children[1..].iter().any(|inside| *inside),
+        }
+    }
+}
+
+/// Constructive solid geometry: children combined by a boolean operation.
+/// Children must be closed, so that their hits alternate between entering
+/// and leaving them.
+pub struct Csg {
+    operation: Operation,
+    children: Vec<SharedEntity>,
+    material: MaterialKind,
+}
+
+impl Csg {
+    pub fn new(operation: Operation, children: Vec<SharedEntity>, material: MaterialKind) -> Self {
+        assert!(!children.is_empty(), "CSG without children");
+        Csg {
+            operation,
+            children,
+            material,
+        }
+    }
+}
+
+/// Does `ray` leave the surface at `intersection` ?
+fn leaving(ray: &Ray, intersection: &RayIntersection) -> bool {
+    intersection.normal.dot(&ray.dir) > 0.0
+}
+
+impl Entity for Csg {
+    fn material(&self) -> &MaterialKind {
+        &self.material
+    }
+
+    fn aabb(&self) -> Aabb {
+        let mut aabbs = self.children.iter().map(|child| child.aabb());
+        let first = aabbs.next().unwrap();
+        match self.operation {
+            Operation::Union => aabbs.fold(first, |acc, aabb| acc.merged(&aabb)),
+            Operation::Intersection => aabbs.fold(first, |acc, aabb| {
+                acc.intersection(&aabb).unwrap_or_else(Aabb::new_invalid)
+            }),
+            Operation::Difference => first,
+        }
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        self.hits(ray, t_min, t_max).into_iter().next()
+    }
+
+    fn hits(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Vec<RayIntersection> {
+        // Every boundary crossing of every child, in order along the ray
+        let mut events: Vec<(usize, RayIntersection)> = Vec::new();
+        let mut inside = Vec::with_capacity(self.children.len());
+        for (i, child) in self.children.iter().enumerate() {
+            let hits = child.hits(ray, t_min, t_max);
+            // A ray first leaving a child starts inside it
+            inside.push(hits.first().is_some_and(|hit| leaving(ray, hit)));
+            events.extend(hits.into_iter().map(|hit| (i, hit)));
+        }
+        events.sort_by(|(_, a), (_, b)| a.toi.total_cmp(&b.toi));
+
+        let mut was_inside = self.operation.inside(&inside);
+        let mut hits = Vec::new();
+        for (i, mut intersection) in events {
+            inside[i] = !leaving(ray, &intersection);
+            let is_inside = self.operation.inside(&inside);
+            if is_inside == was_inside {
+                continue;
+            }
+            // The outward normal of the result: against the ray when
+            // entering it, along the ray when leaving it (e.g. the inside of
+            // a subtracted child)
+            if leaving(ray, &intersection) == is_inside {
+                intersection.normal = -intersection.normal;
+            }
+            hits.push(intersection);
+            was_inside = is_inside;
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::world::{Sphere, DEFAULT_T_MAX, DEFAULT_T_MIN};
+
+    /// Unit spheres centered on 0 and 1 along the X axis, overlapping on
+    /// [0, 1]
+    fn csg(operation: Operation, reversed: bool) -> Csg {
+        let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let mut children: Vec<SharedEntity> = vec![
+            Arc::new(Sphere::new(Point::origin(), 1.0, material.clone())),
+            Arc::new(Sphere::new(
+                Point::new(1.0, 0.0, 0.0),
+                1.0,
+                material.clone(),
+            )),
+        ];
+        if reversed {
+            children.reverse();
+        }
+        Csg::new(operation, children, material)
+    }
+
+    /// Boundaries of the result along the ray from `x` towards +X: the
+    /// abscissa of the hits, and whether the ray enters the result there
+    fn boundaries(csg: &Csg, x: Scalar) -> Vec<(Scalar, bool)> {
+        let ray = Ray::new(Point::new(x, 0.0, 0.0), Vector::x());
+        csg.hits(&ray, DEFAULT_T_MIN, DEFAULT_T_MAX)
+            .iter()
+            .map(|hit| (x + hit.toi, !leaving(&ray, hit)))
+            .collect()
+    }
+
+    fn assert_boundaries(csg: &Csg, x: Scalar, expected: &[(Scalar, bool)]) {
+        let found = boundaries(csg, x);
+        assert_eq!(found.len(), expected.len(), "{:?} != {:?}", found, expected);
+        for ((a, entering_a), (b, entering_b)) in found.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-4, "{:?} != {:?}", found, expected);
+            assert_eq!(entering_a, entering_b, "{:?} != {:?}", found, expected);
+        }
+    }
+
+    #[test]
+    fn union() {
+        let union = csg(Operation::Union, false);
+        assert_boundaries(&union, -5.0, &[(-1.0, true), (2.0, false)]);
+        // Starting inside both spheres
+        assert_boundaries(&union, 0.5, &[(2.0, false)]);
+        assert_boundaries(&union, 5.0, &[]);
+        let aabb = union.aabb();
+        assert_eq!(aabb.mins, Point::new(-1.0, -1.0, -1.0));
+        assert_eq!(aabb.maxs, Point::new(2.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn intersection() {
+        let intersection = csg(Operation::Intersection, false);
+        assert_boundaries(&intersection, -5.0, &[(0.0, true), (1.0, false)]);
+        assert_boundaries(&intersection, 0.5, &[(1.0, false)]);
+        assert_boundaries(&intersection, 1.5, &[]);
+        // Entering the first sphere is not a boundary of the result
+        let ray = Ray::new(Point::new(-5.0, 0.0, 0.0), Vector::x());
+        assert!(intersection.hit(&ray, 0.0, 4.5).is_none());
+    }
+
+    #[test]
+    fn difference() {
+        let difference = csg(Operation::Difference, false);
+        assert_boundaries(&difference, -5.0, &[(-1.0, true), (0.0, false)]);
+        // The boundary carved by the second sphere faces the hole
+        let ray = Ray::new(Point::new(-5.0, 0.0, 0.0), Vector::x());
+        let hits = difference.hits(&ray, DEFAULT_T_MIN, DEFAULT_T_MAX);
+        assert!((hits[1].normal - Vector::x()).norm() < 1e-4);
+        assert_boundaries(&difference, -0.5, &[(0.0, false)]);
+        assert_boundaries(&difference, 0.5, &[]);
+
+        let difference = csg(Operation::Difference, true);
+        assert_boundaries(&difference, -5.0, &[(1.0, true), (2.0, false)]);
+        assert_boundaries(&difference, 0.5, &[(1.0, true), (2.0, false)]);
+        let hits = difference.hits(&ray, DEFAULT_T_MIN, DEFAULT_T_MAX);
+        assert!((hits[0].normal + Vector::x()).norm() < 1e-4);
+    }
+}
@@ -0,0 +1,203 @@
+//! glTF 2.0 scene loader (`.gltf` and `.glb` files)
+
+use std::path::Path;
+use std::sync::Arc;
+
+use ::gltf::buffer::Data;
+use ::gltf::camera::Projection as GltfProjection;
+use ::gltf::khr_lights_punctual::Kind;
+use ::gltf::mesh::Mode;
+use ::gltf::{Gltf, Node};
+use anyhow::{bail, Context, Result};
+use nalgebra::{Matrix3, Matrix4};
+use parry3d::math::Real;
+
+use crate::camera::Projection;
+use crate::json;
+use crate::light::{Light, SpotLight};
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::Mesh;
+use crate::world::World;
+
+/// Maps a glTF metallic-roughness material onto the closest `MaterialKind`.
+/// Textures are ignored.
+fn material(material: &::gltf::Material) -> MaterialKind {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, _] = pbr.base_color_factor();
+    let base_color = Color::new(r, g, b);
+    let emissive =
+        Color::from(material.emissive_factor()) * material.emissive_strength().unwrap_or(1.0);
+    let transmission = material
+        .transmission()
+        .map_or(0.0, |t| t.transmission_factor());
+
+    if emissive.max() > 0.0 {
+        MaterialKind::DiffuseLight(DiffuseLight::new(emissive))
+    } else if transmission > 0.5 {
+        MaterialKind::Dielectric(Dielectric::new(material.ior().unwrap_or(1.5)))
+    } else if pbr.metallic_factor() > 0.5 {
+        MaterialKind::Metal(Metal::new(base_color, pbr.roughness_factor()))
+    } else {
+        MaterialKind::Lambertian(Lambertian::new(base_color))
+    }
+}
+
+struct Loader {
+    buffers: Vec<Data>,
+    world: World,
+    camera: Option<json::Camera>,
+}
+
+impl Loader {
+    fn node(&mut self, node: Node, parent: &Matrix4<Real>) -> Result<()> {
+        let transform = parent * Matrix4::from(node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            self.mesh(&mesh, &transform)
+                .with_context(|| format!("Invalid mesh {}", mesh.index()))?;
+        }
+
+        // The first camera of the scene is used
+        if let (Some(camera), None) = (node.camera(), &self.camera) {
+            self.camera = Some(node_camera(&camera, &transform));
+        }
+
+        if let Some(light) = node.light() {
+            // Lights shine along -Z in their node space
+            let position = transform.transform_point(&Point::origin());
+            let direction = transform.transform_vector(&-Vector::z()).normalize();
+            let intensity = Color::from(light.color()) * light.intensity();
+            self.world.add_light(match light.kind() {
+                Kind::Point => Light::Point {
+                    position,
+                    intensity,
+                },
+                Kind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                } => Light::Spot(SpotLight {
+                    position,
+                    direction,
+                    intensity,
+                    angle: outer_cone_angle.to_degrees(),
+                    falloff: (outer_cone_angle - inner_cone_angle).to_degrees(),
+                }),
+                Kind::Directional => Light::Directional {
+                    direction,
+                    intensity,
+                },
+            });
+        }
+
+        for child in node.children() {
+            self.node(child, &transform)?;
+        }
+        Ok(())
+    }
+
+    /// Adds each primitive of `mesh`, moved to world space by `transform`
+    fn mesh(&mut self, mesh: &::gltf::Mesh, transform: &Matrix4<Real>) -> Result<()> {
+        let normal_transform = transform
+            .fixed_view::<3, 3>(0, 0)
+            .try_inverse()
+            .map(|m| m.transpose())
+            .unwrap_or_else(Matrix3::identity);
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                eprintln!(
+                    "Primitive {} of mesh {} ignored: {:?} are not supported",
+                    primitive.index(),
+                    mesh.index(),
+                    primitive.mode()
+                );
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+            let vertices: Vec<Point> = reader
+                .read_positions()
+                .context("Primitive without positions")?
+                .map(|p| transform.transform_point(&Point::from(p)))
+                .collect();
+            let normals = reader.read_normals().map(|normals| {
+                normals
+                    .map(|n| normal_transform * Vector::from(n))
+                    .collect()
+            });
+            let uvs = reader
+                .read_tex_coords(0)
+                .map(|uvs| uvs.into_f32().collect());
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+            if !indices.len().is_multiple_of(3) {
+                bail!("{} indices do not make triangles", indices.len());
+            }
+            let indices = indices.chunks(3).map(|t| [t[0], t[1], t[2]]).collect();
+
+            let mut entity =
+                Mesh::new(vertices, indices, normals, material(&primitive.material()))?;
+            if let Some(uvs) = uvs {
+                entity = entity.with_uvs(uvs)?;
+            }
+            self.world.add(Arc::new(entity));
+        }
+        Ok(())
+    }
+}
+
+/// glTF cameras look down their local -Z axis, with +Y up
+fn node_camera(camera: &::gltf::Camera, transform: &Matrix4<Real>) -> json::Camera {
+    let lookfrom = transform.transform_point(&Point::origin());
+    let lookat = lookfrom + transform.transform_vector(&-Vector::z()).normalize();
+    let vup = transform.transform_vector(&Vector::y());
+    let mut settings = json::Camera {
+        lookfrom: Some(lookfrom.coords.as_slice().to_vec()),
+        lookat: Some(lookat.coords.as_slice().to_vec()),
+        vup: Some(vup.as_slice().to_vec()),
+        ..Default::default()
+    };
+    match camera.projection() {
+        GltfProjection::Perspective(perspective) => {
+            settings.vfov = Some(perspective.yfov().to_degrees());
+            settings.aspect_ratio = perspective.aspect_ratio();
+        }
+        GltfProjection::Orthographic(orthographic) => {
+            // The viewport is at the focus distance, so at 1 its half height
+            // is tan(vfov / 2)
+            settings.projection = Some(Projection::Orthographic);
+            settings.vfov = Some(2.0 * orthographic.ymag().atan().to_degrees());
+            settings.focus_dist = Some(1.0);
+            settings.aspect_ratio = Some(orthographic.xmag() / orthographic.ymag());
+        }
+    }
+    settings
+}
+
+/// Loads the default scene of a glTF file (or its first one), with the
+/// camera of its first camera node, if any.
+pub fn load(path: &Path) -> Result<(World, Option<json::Camera>)> {
+    let gltf = Gltf::open(path).with_context(|| format!("Failed to load '{}'", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let buffers = ::gltf::import_buffers(&gltf.document, Some(base), gltf.blob)
+        .with_context(|| format!("Failed to load the buffers of '{}'", path.display()))?;
+    let scene = gltf
+        .document
+        .default_scene()
+        .or_else(|| gltf.document.scenes().next())
+        .with_context(|| format!("No scene in '{}'", path.display()))?;
+
+    let mut loader = Loader {
+        buffers,
+        world: World::new(),
+        camera: None,
+    };
+    for node in scene.nodes() {
+        loader
+            .node(node, &Matrix4::identity())
+            .with_context(|| format!("Invalid scene '{}'", path.display()))?;
+    }
+    Ok((loader.world, loader.camera))
+}
@@ -1,7 +1,13 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::background::EnvironmentDescription;
+use crate::camera::{Integrator, Projection};
+use crate::csg::Operation;
+use crate::light::Light;
 use crate::material;
+use crate::math::Color;
+use crate::sampler::Sampler;
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -9,17 +15,262 @@ pub struct Sphere {
     pub center: Vec<f32>,
     pub radius: f32,
     pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Triangle {
+    /// the 3 vertices, counter-clockwise when seen from the front
+    pub vertices: Vec<Vec<f32>>,
+    /// optional vertex normals, for smooth shading
+    pub normals: Option<Vec<Vec<f32>>>,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct TriMesh {
+    pub vertices: Vec<Vec<f32>>,
+    /// triangles, as indices in `vertices`
+    pub indices: Vec<[u32; 3]>,
+    /// optional vertex normals, indexed like `vertices`
+    pub normals: Option<Vec<Vec<f32>>>,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Cuboid {
+    pub center: Vec<f32>,
+    /// half the size along each axis
+    pub half_extents: Vec<f32>,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+/// Vertical cylinder, rotated by its transform if needed
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Cylinder {
+    pub center: Vec<f32>,
+    pub radius: f32,
+    pub height: f32,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+/// Vertical cone, pointing up. `radius` is the one of its base.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Cone {
+    pub center: Vec<f32>,
+    pub radius: f32,
+    pub height: f32,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+/// Segment from `a` to `b`, inflated by `radius`
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Capsule {
+    pub a: Vec<f32>,
+    pub b: Vec<f32>,
+    pub radius: f32,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+/// Infinite plane going through `point`
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Plane {
+    pub point: Vec<f32>,
+    /// side the plane faces
+    pub normal: Vec<f32>,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+/// Parallelogram spanned by the edges `u` and `v` from `corner`, facing the
+/// side of `u` x `v`
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Quad {
+    pub corner: Vec<f32>,
+    pub u: Vec<f32>,
+    pub v: Vec<f32>,
+    /// only the front face emits light
+    #[serde(default)]
+    pub one_sided: bool,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Disk {
+    pub center: Vec<f32>,
+    /// side the disk faces
+    pub normal: Vec<f32>,
+    pub radius: f32,
+    /// only the front face emits light
+    #[serde(default)]
+    pub one_sided: bool,
+    pub material: String,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+/// Affine transformation: scale, then rotate, then translate
+#[derive(Deserialize, Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub struct Transform {
+    pub translation: Option<Vec<f32>>,
+    /// Euler angles, in degrees, applied around X, then Y, then Z
+    pub rotation: Option<Vec<f32>>,
+    /// Rotation as a unit quaternion (x, y, z, w), instead of `rotation`
+    pub quaternion: Option<Vec<f32>>,
+    /// Scale factor along each axis
+    pub scale: Option<Vec<f32>>,
+}
+
+/// Mesh loaded from a file: Wavefront OBJ, PLY or STL
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct MeshFile {
+    pub path: String,
+    /// Material of the whole mesh. If missing, the materials defined in the
+    /// file are used.
+    pub material: Option<String>,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
+}
+
+/// Another copy of a named shape, sharing its geometry
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Instance {
+    /// Name of the shape
+    pub of: String,
+    /// Applied to the shape instead of its own transform
+    pub transform: Option<Transform>,
+    /// Replaces the material of the shape
+    pub material: Option<String>,
+}
+
+/// Combination of closed shapes by a boolean operation
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Csg {
+    /// union, intersection or difference (the first shape minus the others)
+    pub operation: Operation,
+    pub shapes: Vec<Shape>,
+    /// Defaults to the material of the first shape
+    pub material: Option<String>,
+    /// Name under which instances can refer to the shape
+    pub name: Option<String>,
+    pub transform: Option<Transform>,
 }
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Shape {
     Sphere(Sphere),
+    Triangle(Triangle),
+    TriMesh(TriMesh),
+    Cuboid(Cuboid),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Capsule(Capsule),
+    Plane(Plane),
+    Quad(Quad),
+    Disk(Disk),
+    Mesh(MeshFile),
+    Csg(Csg),
+    Instance(Instance),
+}
+
+/// Color seen by the rays escaping the scene. Environment maps are only
+/// loaded when the world is built.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Background {
+    Solid { color: Color },
+    Gradient { top: Color, bottom: Color },
+    None,
+    Environment(EnvironmentDescription),
+}
+
+/// Camera description. Every field is optional, missing ones fall back to
+/// the defaults, and command line flags take precedence over them.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub struct Camera {
+    pub lookfrom: Option<Vec<f32>>,
+    pub lookat: Option<Vec<f32>>,
+    pub vup: Option<Vec<f32>>,
+    /// vertical field of view, in degrees
+    pub vfov: Option<f32>,
+    /// defocus blur cone angle, in degrees
+    pub defocus_angle: Option<f32>,
+    /// distance to the plane of perfect focus
+    pub focus_dist: Option<f32>,
+    /// perspective (default), orthographic, fisheye or equirectangular
+    pub projection: Option<Projection>,
+    /// random (default), stratified, halton or sobol
+    pub sampler: Option<Sampler>,
+    /// seed of the random number generators
+    pub seed: Option<u64>,
+    /// path (default), photon or sppm
+    pub integrator: Option<Integrator>,
+    /// number of photons shot by the photon integrator, or by every sppm pass
+    pub photons: Option<usize>,
+    pub aspect_ratio: Option<f32>,
+    /// image width, in pixels
+    pub width: Option<usize>,
+    pub samples_per_pixel: Option<usize>,
+    pub max_depth: Option<usize>,
 }
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub struct World {
+    pub camera: Option<Camera>,
+    /// Defaults to a white to blue gradient
+    pub background: Option<Background>,
+    /// Point, spot and directional lights, besides the emissive materials
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    /// Hits closer than this to the origin of a ray are ignored, to avoid
+    /// self-intersections. Must be positive. Defaults to 0.001, scale it with
+    /// the scene
+    pub t_min: Option<f32>,
+    /// Hits further than this are ignored. Unlimited by default
+    pub t_max: Option<f32>,
     pub materials: HashMap<String, material::MaterialKind>,
     pub shapes: Vec<Shape>,
 }
@@ -1,6 +1,19 @@
+pub mod background;
+mod bvh;
 pub mod camera;
 pub mod cli;
+pub mod csg;
+pub mod gltf;
 pub mod json;
-mod material;
-mod math;
+pub mod light;
+pub mod material;
+pub mod math;
+pub mod mesh;
+pub mod obj;
+pub mod photon;
+pub mod ply;
+pub mod primitive;
+pub mod sampler;
+pub mod stl;
+pub mod transform;
 pub mod world;
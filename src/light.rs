@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+use crate::math::*;
+
+/// Light source without any geometry: rays never hit it, surfaces are
+/// connected to it by shadow rays instead
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Light {
+    /// Shines equally in every direction from `position`
+    Point { position: Point, intensity: Color },
+    /// Point light restricted to a cone around `direction`
+    Spot(SpotLight),
+    /// Parallel rays travelling along `direction`, like the sun
+    Directional { direction: Vector, intensity: Color },
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub struct SpotLight {
+    pub position: Point,
+    pub direction: Vector,
+    pub intensity: Color,
+    /// Half-angle of the cone, in degrees
+    pub angle: Scalar,
+    /// Width of the smooth transition at the edge of the cone, in degrees.
+    /// Zero (the default) makes a sharp edge
+    #[serde(default)]
+    pub falloff: Scalar,
+}
+
+impl SpotLight {
+    /// Fraction of the intensity in the unit direction `dir`
+    pub(crate) fn attenuation(&self, dir: &Vector) -> Scalar {
+        let angle = dir.angle(&self.direction.normalize()).to_degrees();
+        if angle >= self.angle {
+            0.0
+        } else if angle <= self.angle - self.falloff {
+            1.0
+        } else {
+            let t = (self.angle - angle) / self.falloff;
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+}
+
+impl Light {
+    /// Light arriving at `point`: the unit direction towards the light, the
+    /// distance to it (infinite for directional lights) and the incident
+    /// radiance. None if the light doesn't reach the point.
+    pub fn sample(&self, point: &Point) -> Option<(Vector, Scalar, Color)> {
+        match self {
+            Light::Point {
+                position,
+                intensity,
+            } => {
+                let to_light = position - point;
+                let distance = to_light.norm();
+                Some((
+                    to_light / distance,
+                    distance,
+                    intensity / (distance * distance),
+                ))
+            }
+            Light::Spot(spot) => {
+                let to_light = spot.position - point;
+                let distance = to_light.norm();
+                let wi = to_light / distance;
+                let attenuation = spot.attenuation(&-wi);
+                (attenuation > 0.0).then(|| {
+                    let radiance = spot.intensity * attenuation / (distance * distance);
+                    (wi, distance, radiance)
+                })
+            }
+            Light::Directional {
+                direction,
+                intensity,
+            } => Some((-direction.normalize(), Scalar::INFINITY, *intensity)),
+        }
+    }
+}
@@ -1,16 +1,99 @@
+use std::ops::BitOr;
+
 use parry3d::query::{Ray, RayIntersection};
 
 use serde::{Deserialize, Serialize};
 
 use crate::math::*;
 
+/// Kinds of lobes of a BSDF, combined with `|`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BsdfFlags(u8);
+
+impl BsdfFlags {
+    pub const NONE: BsdfFlags = BsdfFlags(0);
+    pub const REFLECTION: BsdfFlags = BsdfFlags(1);
+    pub const TRANSMISSION: BsdfFlags = BsdfFlags(2);
+    pub const DIFFUSE: BsdfFlags = BsdfFlags(4);
+    pub const GLOSSY: BsdfFlags = BsdfFlags(8);
+    /// Scatters in a single direction: `eval` and `pdf` can't find it
+    pub const SPECULAR: BsdfFlags = BsdfFlags(16);
+
+    /// Whether any of the lobes of `other` are set
+    pub fn intersects(self, other: BsdfFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Whether light can be gathered from given directions, with `eval`
+    pub fn is_non_specular(self) -> bool {
+        self.intersects(BsdfFlags::DIFFUSE | BsdfFlags::GLOSSY)
+    }
+}
+
+impl BitOr for BsdfFlags {
+    type Output = BsdfFlags;
+
+    fn bitor(self, rhs: BsdfFlags) -> BsdfFlags {
+        BsdfFlags(self.0 | rhs.0)
+    }
+}
+
+/// Incident direction picked by `Material::sample`
+#[derive(Debug, Clone, Copy)]
+pub struct BsdfSample {
+    pub wi: Vector,
+    /// Value of the BSDF
+    pub f: Color,
+    /// Density of `wi`, per unit solid angle. For specular lobes, the
+    /// probability to pick that lobe
+    pub pdf: Scalar,
+    pub flags: BsdfFlags,
+}
+
+impl BsdfSample {
+    /// Fraction of the light coming from `wi` that the sample carries
+    pub fn weight(&self) -> Color {
+        self.f * self.wi.z.abs() / self.pdf
+    }
+}
+
+/// Materials are described by their BSDF. Directions are unit vectors in the
+/// local shading frame, where z is the outward normal, and point away from
+/// the surface: `wo` towards the viewer, `wi` towards the light.
 pub trait Material {
-    fn scatter(
-        &self,
-        rng: &mut rand::rngs::ThreadRng,
-        ray_in: &Ray,
-        intersection: &RayIntersection,
-    ) -> Option<(Color, Ray)>;
+    /// Lobes of the BSDF
+    fn flags(&self) -> BsdfFlags;
+
+    /// Picks an incident direction for `wo`, from two uniform numbers in
+    /// [0, 1). None if the light is absorbed.
+    fn sample(&self, wo: &Vector, u: (Scalar, Scalar)) -> Option<BsdfSample>;
+
+    /// Fraction of the light arriving from `wi` that leaves along `wo`.
+    /// Black by default, for specular materials
+    fn eval(&self, _wo: &Vector, _wi: &Vector) -> Color {
+        Color::zeros()
+    }
+
+    /// Density of `sample` picking `wi`, per unit solid angle. Zero by
+    /// default, for specular materials
+    fn pdf(&self, _wo: &Vector, _wi: &Vector) -> Scalar {
+        0.0
+    }
+
+    /// Light emitted by the material at the intersection. Black by default
+    fn emitted(&self, _ray_in: &Ray, _intersection: &RayIntersection) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+}
+
+/// Whether `wo` and `wi` are on the same side of the surface
+fn same_hemisphere(wo: &Vector, wi: &Vector) -> bool {
+    wo.z * wi.z > 0.0
+}
+
+/// Mirror direction of `wo` around the normal
+fn reflect(wo: &Vector) -> Vector {
+    Vector::new(-wo.x, -wo.y, wo.z)
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -19,28 +102,50 @@ pub struct Lambertian {
     albedo: Color,
 }
 
-// impl Lambertian {
-//     pub fn new(albedo: Color) -> Lambertian {
-//         Lambertian { albedo }
-//     }
-// }
+impl Lambertian {
+    pub fn new(albedo: Color) -> Lambertian {
+        Lambertian { albedo }
+    }
+}
 
 impl Material for Lambertian {
-    fn scatter(
-        &self,
-        rng: &mut rand::rngs::ThreadRng,
-        ray_in: &Ray,
-        intersection: &RayIntersection,
-    ) -> Option<(Color, Ray)> {
-        let mut scatter_direction = intersection.normal + random_unit_vector(rng);
-        if vector_near_zero(&scatter_direction) {
-            scatter_direction = intersection.normal;
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::REFLECTION | BsdfFlags::DIFFUSE
+    }
+
+    fn sample(&self, wo: &Vector, u: (Scalar, Scalar)) -> Option<BsdfSample> {
+        let mut wi = cosine_sample_hemisphere(u.0, u.1);
+        if wo.z < 0.0 {
+            wi.z = -wi.z;
+        }
+        let pdf = self.pdf(wo, &wi);
+        (pdf > 0.0).then(|| BsdfSample {
+            wi,
+            f: self.eval(wo, &wi),
+            pdf,
+            flags: self.flags(),
+        })
+    }
+
+    fn eval(&self, wo: &Vector, wi: &Vector) -> Color {
+        if same_hemisphere(wo, wi) {
+            self.albedo / std::f32::consts::PI
+        } else {
+            Color::zeros()
+        }
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> Scalar {
+        if same_hemisphere(wo, wi) {
+            wi.z.abs() / std::f32::consts::PI
+        } else {
+            0.0
         }
-        let scattered = Ray::new(ray_in.point_at(intersection.toi), scatter_direction);
-        Some((self.albedo, scattered))
     }
 }
 
+/// Mirror reflection, blurred by `fuzz`: the reflected direction is moved to
+/// a random point of a sphere of radius `fuzz` around its tip
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub struct Metal {
@@ -48,31 +153,161 @@ pub struct Metal {
     fuzz: f32,
 }
 
-// impl Metal {
-//     pub fn new(albedo: Color, fuzz: f32) -> Metal {
-//         Metal {
-//             albedo,
-//             fuzz: fuzz.clamp(0.0, 1.0),
-//         }
-//     }
-// }
+impl Metal {
+    pub fn new(albedo: Color, fuzz: f32) -> Metal {
+        Metal {
+            albedo,
+            fuzz: fuzz.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Density of the fuzzy reflection of `wo` being `wi`: the ray from the
+    /// origin along `wi` crosses the fuzz sphere at up to two points,
+    /// uniformly distributed on it
+    fn fuzz_pdf(&self, wo: &Vector, wi: &Vector) -> Scalar {
+        let c = wi.dot(&reflect(wo));
+        let discriminant = c * c - 1.0 + self.fuzz * self.fuzz;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let root = discriminant.sqrt();
+        let (near, far) = ((c - root).max(0.0), c + root);
+        if far <= 0.0 {
+            return 0.0;
+        }
+        (near * near + far * far) / (4.0 * std::f32::consts::PI * self.fuzz * root)
+    }
+}
 
 impl Material for Metal {
-    fn scatter(
-        &self,
-        rng: &mut rand::rngs::ThreadRng,
-        ray_in: &Ray,
-        intersection: &RayIntersection,
-    ) -> Option<(Color, Ray)> {
-        // TODO: do the math, should I normalize ray_in.dir ?
-        let reflected = vector_reflect(&ray_in.dir, &intersection.normal);
-        let hit = ray_in.point_at(intersection.toi);
-        let scattered = Ray::new(hit, reflected + self.fuzz * random_unit_vector(rng));
-        if scattered.dir.dot(&intersection.normal) > 0.0 {
-            Some((self.albedo, scattered))
+    fn flags(&self) -> BsdfFlags {
+        if self.fuzz == 0.0 {
+            BsdfFlags::REFLECTION | BsdfFlags::SPECULAR
         } else {
-            None
+            BsdfFlags::REFLECTION | BsdfFlags::GLOSSY
+        }
+    }
+
+    fn sample(&self, wo: &Vector, u: (Scalar, Scalar)) -> Option<BsdfSample> {
+        if self.fuzz == 0.0 {
+            let wi = reflect(wo);
+            return Some(BsdfSample {
+                wi,
+                f: self.albedo / wi.z.abs(),
+                pdf: 1.0,
+                flags: self.flags(),
+            });
+        }
+        // Reflections going below the surface are absorbed
+        let wi = (reflect(wo) + self.fuzz * uniform_sample_sphere(u.0, u.1)).normalize();
+        let pdf = self.pdf(wo, &wi);
+        (pdf > 0.0).then(|| BsdfSample {
+            wi,
+            f: self.eval(wo, &wi),
+            pdf,
+            flags: self.flags(),
+        })
+    }
+
+    fn eval(&self, wo: &Vector, wi: &Vector) -> Color {
+        if self.fuzz == 0.0 || !same_hemisphere(wo, wi) {
+            return Color::zeros();
         }
+        self.albedo * self.fuzz_pdf(wo, wi) / wi.z.abs()
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> Scalar {
+        if self.fuzz == 0.0 || !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+        self.fuzz_pdf(wo, wi)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct Dielectric {
+    /// Index of refraction of the material, relative to the outside medium
+    refraction_index: f32,
+}
+
+impl Dielectric {
+    pub fn new(refraction_index: f32) -> Dielectric {
+        Dielectric { refraction_index }
+    }
+
+    fn reflectance(cosine: f32, refraction_index: f32) -> f32 {
+        // Use Schlick's approximation for reflectance.
+        let r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
+        let r0 = r0 * r0;
+        r0 + (1.0 - r0) * f32::powi(1.0 - cosine, 5)
+    }
+}
+
+impl Material for Dielectric {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::REFLECTION | BsdfFlags::TRANSMISSION | BsdfFlags::SPECULAR
+    }
+
+    fn sample(&self, wo: &Vector, u: (Scalar, Scalar)) -> Option<BsdfSample> {
+        // The ray arrives from outside when `wo` is on the side of the normal
+        let entering = wo.z > 0.0;
+        let ri = if entering {
+            1.0 / self.refraction_index
+        } else {
+            self.refraction_index
+        };
+        let normal = if entering { Vector::z() } else { -Vector::z() };
+
+        let cos_theta = wo.z.abs().min(1.0);
+        let sin_theta = f32::sqrt(1.0 - cos_theta * cos_theta);
+
+        // Total internal reflection, or Fresnel reflection. The light is
+        // never absorbed: the chosen lobe carries all of it
+        let cannot_refract = ri * sin_theta > 1.0;
+        let reflectance = if cannot_refract {
+            1.0
+        } else {
+            Self::reflectance(cos_theta, ri)
+        };
+        let (wi, pdf, flags) = if u.0 < reflectance {
+            (reflect(wo), reflectance, BsdfFlags::REFLECTION)
+        } else {
+            let wi = vector_refract(&-wo, &normal, ri);
+            (wi, 1.0 - reflectance, BsdfFlags::TRANSMISSION)
+        };
+        Some(BsdfSample {
+            wi,
+            f: Color::repeat(pdf / wi.z.abs()),
+            pdf,
+            flags: flags | BsdfFlags::SPECULAR,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub struct DiffuseLight {
+    emit: Color,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Color) -> DiffuseLight {
+        DiffuseLight { emit }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::NONE
+    }
+
+    fn sample(&self, _wo: &Vector, _u: (Scalar, Scalar)) -> Option<BsdfSample> {
+        None
+    }
+
+    fn emitted(&self, _ray_in: &Ray, _intersection: &RayIntersection) -> Color {
+        self.emit
     }
 }
 
@@ -80,18 +315,80 @@ impl Material for Metal {
 pub enum MaterialKind {
     Lambertian(Lambertian),
     Metal(Metal),
+    Dielectric(Dielectric),
+    DiffuseLight(DiffuseLight),
+}
+
+impl MaterialKind {
+    /// Same material, with a different albedo. Materials without albedo are
+    /// left unchanged.
+    pub fn with_albedo(&self, albedo: Color) -> MaterialKind {
+        match self {
+            MaterialKind::Lambertian(_) => MaterialKind::Lambertian(Lambertian { albedo }),
+            MaterialKind::Metal(mat) => MaterialKind::Metal(Metal { albedo, ..*mat }),
+            MaterialKind::Dielectric(_) | MaterialKind::DiffuseLight(_) => self.clone(),
+        }
+    }
+
+    /// Whether the material emits light
+    pub fn is_emissive(&self) -> bool {
+        matches!(self, MaterialKind::DiffuseLight(light) if light.emit != Color::zeros())
+    }
+
+    /// Same material, without any light emission
+    pub fn without_emission(&self) -> MaterialKind {
+        match self {
+            MaterialKind::DiffuseLight(_) => {
+                MaterialKind::DiffuseLight(DiffuseLight::new(Color::zeros()))
+            }
+            _ => self.clone(),
+        }
+    }
 }
 
 impl Material for MaterialKind {
-    fn scatter(
-        &self,
-        rng: &mut rand::rngs::ThreadRng,
-        ray_in: &Ray,
-        intersection: &RayIntersection,
-    ) -> Option<(Color, Ray)> {
+    fn flags(&self) -> BsdfFlags {
+        match self {
+            MaterialKind::Lambertian(mat) => mat.flags(),
+            MaterialKind::Metal(mat) => mat.flags(),
+            MaterialKind::Dielectric(mat) => mat.flags(),
+            MaterialKind::DiffuseLight(mat) => mat.flags(),
+        }
+    }
+
+    fn sample(&self, wo: &Vector, u: (Scalar, Scalar)) -> Option<BsdfSample> {
+        match self {
+            MaterialKind::Lambertian(mat) => mat.sample(wo, u),
+            MaterialKind::Metal(mat) => mat.sample(wo, u),
+            MaterialKind::Dielectric(mat) => mat.sample(wo, u),
+            MaterialKind::DiffuseLight(mat) => mat.sample(wo, u),
+        }
+    }
+
+    fn eval(&self, wo: &Vector, wi: &Vector) -> Color {
+        match self {
+            MaterialKind::Lambertian(mat) => mat.eval(wo, wi),
+            MaterialKind::Metal(mat) => mat.eval(wo, wi),
+            MaterialKind::Dielectric(mat) => mat.eval(wo, wi),
+            MaterialKind::DiffuseLight(mat) => mat.eval(wo, wi),
+        }
+    }
+
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> Scalar {
+        match self {
+            MaterialKind::Lambertian(mat) => mat.pdf(wo, wi),
+            MaterialKind::Metal(mat) => mat.pdf(wo, wi),
+            MaterialKind::Dielectric(mat) => mat.pdf(wo, wi),
+            MaterialKind::DiffuseLight(mat) => mat.pdf(wo, wi),
+        }
+    }
+
+    fn emitted(&self, ray_in: &Ray, intersection: &RayIntersection) -> Color {
         match self {
-            MaterialKind::Lambertian(mat) => mat.scatter(rng, ray_in, intersection),
-            MaterialKind::Metal(mat) => mat.scatter(rng, ray_in, intersection),
+            MaterialKind::Lambertian(mat) => mat.emitted(ray_in, intersection),
+            MaterialKind::Metal(mat) => mat.emitted(ray_in, intersection),
+            MaterialKind::Dielectric(mat) => mat.emitted(ray_in, intersection),
+            MaterialKind::DiffuseLight(mat) => mat.emitted(ray_in, intersection),
         }
     }
 }
@@ -1,3 +1,4 @@
+use anyhow::{bail, Result};
 use parry3d::na;
 
 pub type Scalar = f32;
@@ -6,50 +7,132 @@ pub type Vector = na::Vector3<Scalar>;
 pub type Color = Vector;
 pub type Isometry = na::Isometry3<f32>;
 
-use rand::Rng;
+use rand::SeedableRng;
 
-pub fn vector_near_zero(v: &Vector) -> bool {
-    v.x.abs() < Scalar::EPSILON && v.y.abs() < Scalar::EPSILON && v.z.abs() < Scalar::EPSILON
+/// Random number generator used by the renderer. It is cheap to create, so
+/// that each sample gets its own, seeded from its pixel and sample index.
+pub type Prng = rand_pcg::Pcg32;
+
+/// Mixes two integers into a well distributed 64 bits hash (splitmix64 finalizer)
+pub(crate) fn hash(a: u64, b: u64) -> u64 {
+    let mut z = a
+        .wrapping_mul(0x9e3779b97f4a7c15)
+        .wrapping_add(b)
+        .wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Returns the generator of the `sample`-th sample of `pixel`, such that a
+/// render only depends on `seed` and not on thread scheduling.
+pub fn sample_rng(seed: u64, pixel: u64, sample: u64) -> Prng {
+    Prng::seed_from_u64(hash(hash(seed, pixel), sample))
 }
 
-pub fn vector_reflect(v: &Vector, n: &Vector) -> Vector {
-    v - 2.0 * v.dot(n) * n
+pub fn vec_to_point(vec: &[f32]) -> Result<Point> {
+    let [x, y, z] = vec[..] else {
+        bail!("Expected 3 coordinates (x, y, z), got {}", vec.len());
+    };
+    Ok(Point::new(x, y, z))
 }
 
-#[allow(dead_code)]
-pub fn random_vector(rng: &mut rand::rngs::ThreadRng) -> Vector {
-    Vector::new(rng.gen(), rng.gen(), rng.gen())
+pub fn vec_to_vector(vec: &[f32]) -> Result<Vector> {
+    Ok(vec_to_point(vec)?.coords)
 }
 
-fn random_vector_rang(rng: &mut rand::rngs::ThreadRng, min: Scalar, max: Scalar) -> Vector {
-    let x: f32 = rng.gen_range(min..max);
-    let y: f32 = rng.gen_range(min..max);
-    let z: f32 = rng.gen_range(min..max);
-    Vector::new(x, y, z)
+pub fn vector_refract(uv: &Vector, n: &Vector, etai_over_etat: Scalar) -> Vector {
+    let cos_theta = Scalar::min(-uv.dot(n), 1.0);
+    let r_out_perp = etai_over_etat * (uv + cos_theta * n);
+    let r_out_parallel = -Scalar::sqrt(Scalar::abs(1.0 - r_out_perp.norm_squared())) * n;
+    r_out_perp + r_out_parallel
 }
 
-fn random_vector_in_unit_sphere(rng: &mut rand::rngs::ThreadRng) -> Vector {
-    loop {
-        let v = random_vector_rang(rng, -1.0, 1.0);
-        if v.norm_squared() < 1.0 {
-            break v;
-        }
+/// Returns the normal facing against `dir`, and whether `dir` hits the front
+/// face, that is the side `normal` points to.
+pub fn face_forward(normal: &Vector, dir: &Vector) -> (Vector, bool) {
+    if dir.dot(normal) < 0.0 {
+        (*normal, true)
+    } else {
+        (-normal, false)
     }
 }
 
-pub fn random_unit_vector(rng: &mut rand::rngs::ThreadRng) -> Vector {
-    random_vector_in_unit_sphere(rng).normalize()
+/// Two unit vectors completing the unit vector `n` into an orthonormal
+/// basis, from Duff et al. "Building an Orthonormal Basis, Revisited"
+pub fn orthonormal_basis(n: &Vector) -> (Vector, Vector) {
+    let sign = Scalar::copysign(1.0, n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        Vector::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vector::new(b, sign + n.y * n.y * a, -n.y),
+    )
 }
 
-#[allow(dead_code)]
-pub fn random_unit_vector_on_hemisphere(
-    rng: &mut rand::rngs::ThreadRng,
-    normal: &Vector,
-) -> Vector {
-    let unit = random_unit_vector(rng);
-    if unit.dot(normal) > 0.0 {
-        unit
-    } else {
-        -unit
+/// Local shading frame, with z along the normal
+pub struct Frame {
+    s: Vector,
+    t: Vector,
+    n: Vector,
+}
+
+impl Frame {
+    /// Frame around the unit `normal`
+    pub fn new(normal: &Vector) -> Self {
+        let (s, t) = orthonormal_basis(normal);
+        Frame { s, t, n: *normal }
+    }
+
+    pub fn to_local(&self, v: &Vector) -> Vector {
+        Vector::new(v.dot(&self.s), v.dot(&self.t), v.dot(&self.n))
+    }
+
+    pub fn to_world(&self, v: &Vector) -> Vector {
+        v.x * self.s + v.y * self.t + v.z * self.n
     }
 }
+
+/// Maps a point of [0, 1)^2 onto the unit disk, preserving stratification
+/// (Shirley's concentric mapping)
+pub fn concentric_sample_disk(u: Scalar, v: Scalar) -> Vector {
+    let ox = 2.0 * u - 1.0;
+    let oy = 2.0 * v - 1.0;
+    if ox == 0.0 && oy == 0.0 {
+        return Vector::zeros();
+    }
+    let (r, theta) = if ox.abs() > oy.abs() {
+        (ox, std::f32::consts::FRAC_PI_4 * (oy / ox))
+    } else {
+        (
+            oy,
+            std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (ox / oy),
+        )
+    };
+    Vector::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+/// Maps a point of [0, 1)^2 onto the upper unit hemisphere, with a density
+/// proportional to the cosine with the z axis
+pub fn cosine_sample_hemisphere(u: Scalar, v: Scalar) -> Vector {
+    let d = concentric_sample_disk(u, v);
+    let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
+    Vector::new(d.x, d.y, z)
+}
+
+/// Maps a point of [0, 1)^2 onto the unit sphere, uniformly
+pub fn uniform_sample_sphere(u: Scalar, v: Scalar) -> Vector {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = std::f32::consts::TAU * v;
+    Vector::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Maps a point of [0, 1)^2 onto the unit directions within the cone of
+/// cosine `cos_max` around the z axis, uniformly
+pub fn uniform_sample_cone(u: Scalar, v: Scalar, cos_max: Scalar) -> Vector {
+    let z = 1.0 - u * (1.0 - cos_max);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = std::f32::consts::TAU * v;
+    Vector::new(r * phi.cos(), r * phi.sin(), z)
+}
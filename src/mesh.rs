@@ -0,0 +1,316 @@
+use anyhow::{bail, Result};
+use parry3d::bounding_volume::Aabb;
+use parry3d::query::{Ray, RayCast, RayIntersection};
+use parry3d::shape::{FeatureId, Shape, TriMesh};
+
+use crate::material::*;
+use crate::math::*;
+use crate::world::Entity;
+
+/// Barycentric coordinates of `p`, assumed to lie in the triangle (a, b, c)
+fn barycentric(a: &Point, b: &Point, c: &Point, p: &Point) -> (Scalar, Scalar, Scalar) {
+    let v0 = b - a;
+    let v1 = c - a;
+    let v2 = p - a;
+    let d00 = v0.dot(&v0);
+    let d01 = v0.dot(&v1);
+    let d11 = v1.dot(&v1);
+    let d20 = v2.dot(&v0);
+    let d21 = v2.dot(&v1);
+    let denom = d00 * d11 - d01 * d01;
+    if denom == 0.0 {
+        return (1.0, 0.0, 0.0);
+    }
+    let v = (d11 * d20 - d01 * d21) / denom;
+    let w = (d00 * d21 - d01 * d20) / denom;
+    (1.0 - v - w, v, w)
+}
+
+/// Normal of the triangle (a, b, c) at `p`: the interpolated vertex normals
+/// if any, the geometric normal (counter-clockwise winding) otherwise.
+fn triangle_normal(
+    a: &Point,
+    b: &Point,
+    c: &Point,
+    normals: Option<[&Vector; 3]>,
+    p: &Point,
+) -> Vector {
+    match normals {
+        Some([na, nb, nc]) => {
+            let (u, v, w) = barycentric(a, b, c, p);
+            (u * na + v * nb + w * nc).normalize()
+        }
+        None => (b - a).cross(&(c - a)).normalize(),
+    }
+}
+
+/// Point uniformly distributed over the triangle (a, b, c), from two uniform
+/// numbers in [0, 1)
+fn sample_triangle(a: &Point, b: &Point, c: &Point, u: (Scalar, Scalar)) -> Point {
+    let su = u.0.sqrt();
+    let (v, w) = (1.0 - su, u.1 * su);
+    a + v * (b - a) + w * (c - a)
+}
+
+fn triangle_area(a: &Point, b: &Point, c: &Point) -> Scalar {
+    0.5 * (b - a).cross(&(c - a)).norm()
+}
+
+pub struct Triangle {
+    triangle: parry3d::shape::Triangle,
+    normals: Option<[Vector; 3]>,
+    material: MaterialKind,
+}
+
+impl Triangle {
+    /// Optional `normals` are the vertex normals, for smooth shading.
+    pub fn new(vertices: [Point; 3], normals: Option<[Vector; 3]>, material: MaterialKind) -> Self {
+        let [a, b, c] = vertices;
+        Triangle {
+            triangle: parry3d::shape::Triangle::new(a, b, c),
+            normals: normals.map(|ns| ns.map(|n| n.normalize())),
+            material,
+        }
+    }
+}
+
+impl Entity for Triangle {
+    fn material(&self) -> &MaterialKind {
+        &self.material
+    }
+
+    fn aabb(&self) -> Aabb {
+        self.triangle.compute_local_aabb()
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        let shifted = Ray::new(ray.point_at(t_min), ray.dir);
+        let mut intersection =
+            self.triangle
+                .cast_local_ray_and_get_normal(&shifted, t_max - t_min, false)?;
+        intersection.toi += t_min;
+        let parry3d::shape::Triangle { a, b, c } = &self.triangle;
+        let normals = self.normals.as_ref().map(|[na, nb, nc]| [na, nb, nc]);
+        intersection.normal = triangle_normal(a, b, c, normals, &ray.point_at(intersection.toi));
+        intersection.feature = FeatureId::Face(0);
+        Some(intersection)
+    }
+
+    fn geometric_normal(&self, _ray: &Ray, _intersection: &RayIntersection) -> Vector {
+        let parry3d::shape::Triangle { a, b, c } = &self.triangle;
+        triangle_normal(a, b, c, None, a)
+    }
+
+    fn area(&self) -> Option<Scalar> {
+        let parry3d::shape::Triangle { a, b, c } = &self.triangle;
+        Some(triangle_area(a, b, c))
+    }
+
+    fn sample(&self, u: (Scalar, Scalar)) -> Option<(Point, Vector)> {
+        let parry3d::shape::Triangle { a, b, c } = &self.triangle;
+        Some((
+            sample_triangle(a, b, c, u),
+            triangle_normal(a, b, c, None, a),
+        ))
+    }
+}
+
+/// Indexed triangle mesh
+pub struct Mesh {
+    trimesh: TriMesh,
+    normals: Option<Vec<Vector>>,
+    uvs: Option<Vec<[Scalar; 2]>>,
+    /// Per-vertex albedo, overriding the one of the material
+    colors: Option<Vec<Color>>,
+    /// Cumulated areas of the triangles, to sample them by area
+    areas: Vec<Scalar>,
+    material: MaterialKind,
+}
+
+impl Mesh {
+    /// Optional `normals` are the vertex normals, for smooth shading. They
+    /// are indexed like `vertices`.
+    pub fn new(
+        vertices: Vec<Point>,
+        indices: Vec<[u32; 3]>,
+        normals: Option<Vec<Vector>>,
+        material: MaterialKind,
+    ) -> Result<Self> {
+        if indices.is_empty() {
+            bail!("Mesh without any triangle");
+        }
+        if let Some(index) = indices
+            .iter()
+            .flatten()
+            .find(|i| **i as usize >= vertices.len())
+        {
+            bail!(
+                "Mesh vertex index {} out of bounds ({} vertices)",
+                index,
+                vertices.len()
+            );
+        }
+        if let Some(normals) = &normals {
+            if normals.len() != vertices.len() {
+                bail!(
+                    "Mesh has {} normals for {} vertices",
+                    normals.len(),
+                    vertices.len()
+                );
+            }
+        }
+        let areas = indices
+            .iter()
+            .scan(0.0, |total, [ia, ib, ic]| {
+                let [a, b, c] = [ia, ib, ic].map(|i| &vertices[*i as usize]);
+                *total += triangle_area(a, b, c);
+                Some(*total)
+            })
+            .collect();
+        Ok(Mesh {
+            trimesh: TriMesh::new(vertices, indices),
+            areas,
+            normals: normals.map(|ns| ns.into_iter().map(|n| n.normalize()).collect()),
+            uvs: None,
+            colors: None,
+            material,
+        })
+    }
+
+    /// Adds texture coordinates to the mesh. They are indexed like the
+    /// vertices.
+    pub fn with_uvs(mut self, uvs: Vec<[Scalar; 2]>) -> Result<Self> {
+        let nr_vertices = self.trimesh.vertices().len();
+        if uvs.len() != nr_vertices {
+            bail!(
+                "Mesh has {} texture coordinates for {} vertices",
+                uvs.len(),
+                nr_vertices
+            );
+        }
+        self.uvs = Some(uvs);
+        Ok(self)
+    }
+
+    /// Adds per-vertex colors, used as the albedo of the material. They are
+    /// indexed like the vertices.
+    pub fn with_colors(mut self, colors: Vec<Color>) -> Result<Self> {
+        let nr_vertices = self.trimesh.vertices().len();
+        if colors.len() != nr_vertices {
+            bail!(
+                "Mesh has {} colors for {} vertices",
+                colors.len(),
+                nr_vertices
+            );
+        }
+        self.colors = Some(colors);
+        Ok(self)
+    }
+
+    /// Interpolated texture coordinates at `point`, on the triangle `face`
+    pub fn uv(&self, face: u32, point: &Point) -> Option<[Scalar; 2]> {
+        let uvs = self.uvs.as_ref()?;
+        let [ia, ib, ic] = self
+            .trimesh
+            .indices()
+            .get(face as usize)?
+            .map(|i| i as usize);
+        let vertices = self.trimesh.vertices();
+        let (u, v, w) = barycentric(&vertices[ia], &vertices[ib], &vertices[ic], point);
+        Some([0, 1].map(|k| u * uvs[ia][k] + v * uvs[ib][k] + w * uvs[ic][k]))
+    }
+}
+
+impl Entity for Mesh {
+    fn material(&self) -> &MaterialKind {
+        &self.material
+    }
+
+    fn aabb(&self) -> Aabb {
+        *self.trimesh.local_aabb()
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        let shifted = Ray::new(ray.point_at(t_min), ray.dir);
+        let mut intersection =
+            self.trimesh
+                .cast_local_ray_and_get_normal(&shifted, t_max - t_min, false)?;
+        intersection.toi += t_min;
+
+        // Back faces are reported with an offset, see `TriMesh::is_backface`
+        let nr_triangles = self.trimesh.indices().len() as u32;
+        let face = match intersection.feature {
+            FeatureId::Face(face) => face % nr_triangles,
+            _ => return Some(intersection),
+        };
+        let [ia, ib, ic] = self.trimesh.indices()[face as usize];
+        let vertices = self.trimesh.vertices();
+        let normals = self
+            .normals
+            .as_ref()
+            .map(|ns| [&ns[ia as usize], &ns[ib as usize], &ns[ic as usize]]);
+        intersection.normal = triangle_normal(
+            &vertices[ia as usize],
+            &vertices[ib as usize],
+            &vertices[ic as usize],
+            normals,
+            &ray.point_at(intersection.toi),
+        );
+        intersection.feature = FeatureId::Face(face);
+        Some(intersection)
+    }
+
+    fn color(&self, ray: &Ray, intersection: &RayIntersection) -> Option<Color> {
+        let colors = self.colors.as_ref()?;
+        let FeatureId::Face(face) = intersection.feature else {
+            return None;
+        };
+        let [ia, ib, ic] = self.trimesh.indices()[face as usize].map(|i| i as usize);
+        let vertices = self.trimesh.vertices();
+        let p = ray.point_at(intersection.toi);
+        let (u, v, w) = barycentric(&vertices[ia], &vertices[ib], &vertices[ic], &p);
+        Some(u * colors[ia] + v * colors[ib] + w * colors[ic])
+    }
+
+    fn uv_at(&self, ray: &Ray, intersection: &RayIntersection) -> Option<[Scalar; 2]> {
+        let FeatureId::Face(face) = intersection.feature else {
+            return None;
+        };
+        self.uv(face, &ray.point_at(intersection.toi))
+    }
+
+    fn geometric_normal(&self, _ray: &Ray, intersection: &RayIntersection) -> Vector {
+        let FeatureId::Face(face) = intersection.feature else {
+            return intersection.normal;
+        };
+        let [a, b, c] =
+            self.trimesh.indices()[face as usize].map(|i| &self.trimesh.vertices()[i as usize]);
+        triangle_normal(a, b, c, None, a)
+    }
+
+    fn area(&self) -> Option<Scalar> {
+        self.areas.last().copied()
+    }
+
+    fn sample(&self, u: (Scalar, Scalar)) -> Option<(Point, Vector)> {
+        // Pick a triangle by area, then reuse the first number within it
+        let total = self.area()?;
+        let target = u.0 * total;
+        let face = self
+            .areas
+            .partition_point(|area| *area <= target)
+            .min(self.areas.len() - 1);
+        let start = if face == 0 { 0.0 } else { self.areas[face - 1] };
+        let size = self.areas[face] - start;
+        let u0 = if size > 0.0 {
+            ((target - start) / size).min(1.0)
+        } else {
+            0.0
+        };
+        let [a, b, c] = self.trimesh.indices()[face].map(|i| &self.trimesh.vertices()[i as usize]);
+        Some((
+            sample_triangle(a, b, c, (u0, u.1)),
+            triangle_normal(a, b, c, None, a),
+        ))
+    }
+}
@@ -0,0 +1,379 @@
+//! Wavefront OBJ loader, with support for MTL material libraries
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::SplitWhitespace;
+
+use anyhow::{bail, Context, Result};
+
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::Mesh;
+
+/// Default diffuse color of MTL materials without `Kd`
+const DEFAULT_DIFFUSE: Scalar = 0.8;
+
+/// Triangles of an OBJ file sharing the same material
+struct Group {
+    material: Option<String>,
+    vertices: Vec<Point>,
+    normals: Vec<Vector>,
+    uvs: Vec<[Scalar; 2]>,
+    indices: Vec<[u32; 3]>,
+    /// Did every corner come with a normal, resp. texture coordinates ?
+    has_normals: bool,
+    has_uvs: bool,
+    /// Vertex index of each (position, texture coordinates, normal) triplet
+    corners: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+}
+
+impl Group {
+    fn new(material: Option<String>) -> Self {
+        Group {
+            material,
+            vertices: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            indices: Vec::new(),
+            has_normals: true,
+            has_uvs: true,
+            corners: HashMap::new(),
+        }
+    }
+}
+
+/// Data shared by all the groups of an OBJ file
+#[derive(Default)]
+struct Obj {
+    positions: Vec<Point>,
+    normals: Vec<Vector>,
+    uvs: Vec<[Scalar; 2]>,
+    groups: Vec<Group>,
+    materials: HashMap<String, MaterialKind>,
+}
+
+impl Obj {
+    fn current_group(&mut self) -> &mut Group {
+        if self.groups.is_empty() {
+            self.groups.push(Group::new(None));
+        }
+        self.groups.last_mut().unwrap()
+    }
+
+    fn use_material(&mut self, name: &str) {
+        let name = Some(name.to_string());
+        match self.groups.iter().position(|g| g.material == name) {
+            // Keep the current group last
+            Some(index) => {
+                let group = self.groups.remove(index);
+                self.groups.push(group);
+            }
+            None => self.groups.push(Group::new(name)),
+        }
+    }
+
+    /// Vertex index of a face corner, `v`, `v/vt`, `v//vn` or `v/vt/vn`
+    fn corner(&mut self, corner: &str) -> Result<u32> {
+        let mut parts = corner.split('/');
+        let position = resolve(parts.next(), self.positions.len(), "vertex")?;
+        let uv = match parts.next() {
+            None | Some("") => None,
+            Some(index) => Some(resolve(Some(index), self.uvs.len(), "texture coordinates")?),
+        };
+        let normal = match parts.next() {
+            None | Some("") => None,
+            Some(index) => Some(resolve(Some(index), self.normals.len(), "normal")?),
+        };
+        if parts.next().is_some() {
+            bail!("Invalid face corner '{}'", corner);
+        }
+
+        let key = (position, uv, normal);
+        let position = self.positions[position];
+        let uv_value = uv.map_or([0.0, 0.0], |i| self.uvs[i]);
+        let normal_value = normal.map_or(Vector::zeros(), |i| self.normals[i]);
+        let group = self.current_group();
+        if let Some(index) = group.corners.get(&key) {
+            return Ok(*index);
+        }
+        let index = group.vertices.len() as u32;
+        group.vertices.push(position);
+        group.has_uvs &= uv.is_some();
+        group.uvs.push(uv_value);
+        group.has_normals &= normal.is_some();
+        group.normals.push(normal_value);
+        group.corners.insert(key, index);
+        Ok(index)
+    }
+
+    fn face(&mut self, corners: SplitWhitespace) -> Result<()> {
+        let corners = corners
+            .map(|corner| self.corner(corner))
+            .collect::<Result<Vec<u32>>>()?;
+        if corners.len() < 3 {
+            bail!("Face with {} vertices", corners.len());
+        }
+        // Polygons are triangulated as a fan
+        let group = self.current_group();
+        for i in 1..corners.len() - 1 {
+            group.indices.push([corners[0], corners[i], corners[i + 1]]);
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str, dir: &Path) -> Result<()> {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let v = floats(tokens, 3)?;
+                self.positions.push(Point::new(v[0], v[1], v[2]));
+            }
+            Some("vn") => {
+                let n = floats(tokens, 3)?;
+                self.normals.push(Vector::new(n[0], n[1], n[2]));
+            }
+            Some("vt") => {
+                let uv = floats(tokens, 1)?;
+                self.uvs.push([uv[0], uv.get(1).copied().unwrap_or(0.0)]);
+            }
+            Some("f") => self.face(tokens)?,
+            Some("usemtl") => match tokens.next() {
+                Some(name) => self.use_material(name),
+                None => bail!("Missing material name"),
+            },
+            Some("mtllib") => {
+                for file in tokens {
+                    self.materials.extend(load_mtl(&dir.join(file))?);
+                }
+            }
+            // Comments, objects, groups, smoothing groups, lines, ... are
+            // not relevant for rendering
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Index of the element referred to by `index` (1-based, or negative to
+/// count from the end) among `len` elements
+fn resolve(index: Option<&str>, len: usize, what: &str) -> Result<usize> {
+    let index = index.context("Empty face corner")?;
+    let i: i64 = index
+        .parse()
+        .with_context(|| format!("Invalid {} index '{}'", what, index))?;
+    let resolved = if i < 0 { len as i64 + i } else { i - 1 };
+    if i == 0 || resolved < 0 || resolved >= len as i64 {
+        bail!("{} index {} out of bounds ({} defined)", what, i, len);
+    }
+    Ok(resolved as usize)
+}
+
+/// Parses the remaining tokens as floats, requiring at least `min` of them
+fn floats(tokens: SplitWhitespace, min: usize) -> Result<Vec<Scalar>> {
+    let values = tokens
+        .map(|t| t.parse().with_context(|| format!("Invalid number '{}'", t)))
+        .collect::<Result<Vec<Scalar>>>()?;
+    if values.len() < min {
+        bail!("Expected at least {} numbers, got {}", min, values.len());
+    }
+    Ok(values)
+}
+
+fn color(tokens: SplitWhitespace) -> Result<Color> {
+    let c = floats(tokens, 3)?;
+    Ok(Color::new(c[0], c[1], c[2]))
+}
+
+/// MTL material description, only keeping what maps to a `MaterialKind`
+struct Mtl {
+    diffuse: Color,
+    specular: Color,
+    /// Specular exponent, in [0, 1000]
+    exponent: Scalar,
+    emission: Color,
+}
+
+impl Default for Mtl {
+    fn default() -> Self {
+        Mtl {
+            diffuse: Color::new(DEFAULT_DIFFUSE, DEFAULT_DIFFUSE, DEFAULT_DIFFUSE),
+            specular: Color::zeros(),
+            exponent: 0.0,
+            emission: Color::zeros(),
+        }
+    }
+}
+
+impl From<Mtl> for MaterialKind {
+    fn from(mtl: Mtl) -> Self {
+        if mtl.emission.max() > 0.0 {
+            MaterialKind::DiffuseLight(DiffuseLight::new(mtl.emission))
+        } else if mtl.specular.max() > 0.0 && mtl.specular.max() >= mtl.diffuse.max() {
+            // The sharper the highlight, the less fuzzy the reflection
+            let fuzz = 1.0 - (mtl.exponent / 1000.0).sqrt();
+            MaterialKind::Metal(Metal::new(mtl.specular, fuzz))
+        } else {
+            MaterialKind::Lambertian(Lambertian::new(mtl.diffuse))
+        }
+    }
+}
+
+fn parse_mtl_line(mtls: &mut Vec<(String, Mtl)>, line: &str) -> Result<()> {
+    let mut tokens = line.split_whitespace();
+    let keyword = match tokens.next() {
+        None => return Ok(()),
+        Some(keyword) if keyword.starts_with('#') => return Ok(()),
+        Some(keyword) => keyword,
+    };
+    if keyword == "newmtl" {
+        let name = tokens.next().context("Missing material name")?;
+        mtls.push((name.to_string(), Mtl::default()));
+        return Ok(());
+    }
+    let Some((_, mtl)) = mtls.last_mut() else {
+        bail!("'{}' outside of a material", keyword);
+    };
+    match keyword {
+        "Kd" => mtl.diffuse = color(tokens)?,
+        "Ks" => mtl.specular = color(tokens)?,
+        "Ke" => mtl.emission = color(tokens)?,
+        "Ns" => mtl.exponent = floats(tokens, 1)?[0].clamp(0.0, 1000.0),
+        // Textures, transparency, illumination models, ... are not supported
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Loads the materials of an MTL file: mostly diffuse ones become
+/// `Lambertian`, mostly specular ones `Metal` and emissive ones
+/// `DiffuseLight`.
+pub fn load_mtl(path: &Path) -> Result<HashMap<String, MaterialKind>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let mut mtls: Vec<(String, Mtl)> = Vec::new();
+    for (line_nr, line) in content.lines().enumerate() {
+        parse_mtl_line(&mut mtls, line)
+            .with_context(|| format!("{}:{}", path.display(), line_nr + 1))?;
+    }
+    Ok(mtls
+        .into_iter()
+        .map(|(name, mtl)| (name, mtl.into()))
+        .collect())
+}
+
+/// Loads an OBJ file, as one mesh per material. `material` overrides the
+/// materials of the MTL libraries, if any.
+pub fn load(path: &Path, material: Option<&MaterialKind>) -> Result<Vec<Mesh>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut obj = Obj::default();
+    for (line_nr, line) in content.lines().enumerate() {
+        obj.parse_line(line, dir)
+            .with_context(|| format!("{}:{}", path.display(), line_nr + 1))?;
+    }
+
+    let mut meshes = Vec::new();
+    for group in obj.groups {
+        if group.indices.is_empty() {
+            continue;
+        }
+        let mat = match (material, &group.material) {
+            (Some(mat), _) => mat.clone(),
+            (None, Some(name)) => obj
+                .materials
+                .get(name)
+                .cloned()
+                .with_context(|| format!("{}: unknown material '{}'", path.display(), name))?,
+            (None, None) => bail!("{}: no material for the mesh", path.display()),
+        };
+        let normals = group.has_normals.then_some(group.normals);
+        let mut mesh = Mesh::new(group.vertices, group.indices, normals, mat)?;
+        if group.has_uvs {
+            mesh = mesh.with_uvs(group.uvs)?;
+        }
+        meshes.push(mesh);
+    }
+    if meshes.is_empty() {
+        bail!("{}: no face", path.display());
+    }
+    Ok(meshes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Obj> {
+        let mut obj = Obj::default();
+        for line in text.lines() {
+            obj.parse_line(line, Path::new(""))?;
+        }
+        Ok(obj)
+    }
+
+    const SQUARE: &str = "
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+";
+
+    #[test]
+    fn negative_indices_count_from_the_end() {
+        let positive = parse(&format!("{}f 2 3 4", SQUARE)).unwrap();
+        let negative = parse(&format!("{}f -3 -2 -1", SQUARE)).unwrap();
+        assert_eq!(negative.groups[0].vertices, positive.groups[0].vertices);
+        assert_eq!(negative.groups[0].indices, positive.groups[0].indices);
+        assert_eq!(
+            negative.groups[0].vertices,
+            [
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+                Point::new(0.0, 1.0, 0.0)
+            ]
+        );
+
+        // Relative to the vertices defined so far, not to the whole file
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 5 5 5\nf -4 -3 -1").unwrap();
+        let group = &obj.groups[0];
+        assert_eq!(group.indices, [[0, 1, 2], [0, 1, 3]]);
+        assert_eq!(group.vertices[3], Point::new(5.0, 5.0, 5.0));
+    }
+
+    #[test]
+    fn out_of_bounds_indices() {
+        assert!(parse(&format!("{}f 1 2 5", SQUARE)).is_err());
+        assert!(parse(&format!("{}f 0 1 2", SQUARE)).is_err());
+        assert!(parse(&format!("{}f -5 -2 -1", SQUARE)).is_err());
+        assert!(parse(&format!("{}f 1/1 2/1 3/1", SQUARE)).is_err());
+        assert!(parse(&format!("{}f 1 2", SQUARE)).is_err());
+    }
+
+    #[test]
+    fn polygons_are_triangulated() {
+        let obj = parse(&format!("{}f 1 2 3 4", SQUARE)).unwrap();
+        assert_eq!(obj.groups[0].indices, [[0, 1, 2], [0, 2, 3]]);
+
+        let obj = parse(&format!("{}v 0.5 2 0\nf 1 2 3 5 4", SQUARE)).unwrap();
+        assert_eq!(obj.groups[0].indices, [[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+    }
+
+    #[test]
+    fn corners_with_attributes() {
+        let text = format!(
+            "{}vt 0 0\nvt 1 1\nvn 0 0 1\nf 1/1/1 2/2/1 3/2/1 4/1/1\nf 1//1 3//1 4//1",
+            SQUARE
+        );
+        let obj = parse(&text).unwrap();
+        let group = &obj.groups[0];
+        // Corners sharing position, texture coordinates and normal share
+        // their vertex
+        assert_eq!(group.indices, [[0, 1, 2], [0, 2, 3], [4, 5, 6]]);
+        assert_eq!(group.uvs[1], [1.0, 1.0]);
+        assert!(group.normals.iter().all(|n| *n == Vector::z()));
+        assert!(group.has_normals);
+        assert!(!group.has_uvs);
+    }
+}
@@ -0,0 +1,772 @@
+//! Photon maps (Jensen, "Realistic Image Synthesis Using Photon Mapping"):
+//! photons are shot from the light sources, and stored where they land on
+//! diffuse surfaces. The light they carry is then estimated from their
+//! density. Caustics maps only keep the photons that bounced off specular
+//! entities, global maps keep all the indirect light.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use parry3d::bounding_volume::Aabb;
+use parry3d::query::{Ray, RayIntersection};
+use parry3d::shape::FeatureId;
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::background::Background;
+use crate::light::{Light, SpotLight};
+use crate::material::{BsdfFlags, Material, MaterialKind};
+use crate::math::*;
+use crate::world::{SharedEntity, World};
+
+/// Number of photons used by a density estimation
+const NEAREST_PHOTONS: usize = 50;
+/// Search radius of the density estimation, relative to the radius of the
+/// specular entities bounds
+const MAX_RADIUS: Scalar = 0.05;
+/// Generators of the photons are seeded apart from the ones of the pixels
+const PHOTON_STREAM: u64 = 0x70686f746f6e73;
+/// Radius of the disk the photons of the sun and the background cross in
+/// unbounded scenes, relative to the radius of the bounded entities
+const UNBOUNDED_DISK: Scalar = 10.0;
+
+/// Light carried to a surface
+struct Photon {
+    position: Point,
+    /// Unit direction the photon came from
+    wi: Vector,
+    power: Color,
+    /// Split axis of the kd-tree node
+    axis: u8,
+}
+
+impl Photon {
+    /// Whether the photon came from the side of the surface `wo` leaves
+    /// from, in the shading `frame`: photons on the other side of a thin wall
+    /// don't count
+    fn same_side(&self, frame: &Frame, wo: &Vector) -> bool {
+        frame.to_local(&self.wi).z * wo.z > 0.0
+    }
+
+    /// Power reflected towards `wo` by the diffuse `material`, whose shading
+    /// frame is `frame`
+    fn reflected(&self, frame: &Frame, wo: &Vector, material: &MaterialKind) -> Color {
+        if !self.same_side(frame, wo) {
+            return Color::zeros();
+        }
+        let f = material.eval(wo, &frame.to_local(&self.wi));
+        f.component_mul(&self.power)
+    }
+}
+
+/// Where photons can come from
+enum Source<'a> {
+    Light(&'a Light),
+    Emitter(&'a SharedEntity),
+    Background,
+}
+
+/// Sphere around the entities photons are shot at
+struct Target {
+    center: Point,
+    radius: Scalar,
+    /// Unbounded entities can't be aimed at: the sources at a finite
+    /// distance then shoot photons in every direction, and the sun and the
+    /// background through a wider disk
+    unbounded: bool,
+}
+
+impl Target {
+    fn new(aabb: &Aabb) -> Self {
+        Target {
+            center: aabb.center(),
+            radius: aabb.half_extents().norm().max(1e-3),
+            unbounded: false,
+        }
+    }
+
+    /// Target of the photons lighting the whole `world`, unbounded entities
+    /// included. None if the world is empty
+    fn world(world: &World) -> Option<Self> {
+        let unbounded = world.is_unbounded();
+        let aabb = match world.bounds() {
+            Some(aabb) => aabb,
+            None if unbounded => Aabb::new(Point::new(-1.0, -1.0, -1.0), Point::new(1.0, 1.0, 1.0)),
+            None => return None,
+        };
+        Some(Target {
+            unbounded,
+            ..Target::new(&aabb)
+        })
+    }
+
+    /// Picks a unit direction from `origin` towards the target, uniformly in
+    /// the cone it subtends. Returns the direction and its solid angle
+    /// density.
+    fn direction_from(&self, origin: &Point, u: (Scalar, Scalar)) -> (Vector, Scalar) {
+        let to_center = self.center - origin;
+        let distance = to_center.norm();
+        if self.unbounded || distance <= self.radius {
+            let dir = uniform_sample_sphere(u.0, u.1);
+            return (dir, 1.0 / (4.0 * std::f32::consts::PI));
+        }
+        let axis = to_center / distance;
+        let cos_max = (1.0 - (self.radius / distance).powi(2)).max(0.0).sqrt();
+        let dir = Frame::new(&axis).to_world(&uniform_sample_cone(u.0, u.1, cos_max));
+        (dir, 1.0 / self.solid_angle_from(origin))
+    }
+
+    fn solid_angle_from(&self, origin: &Point) -> Scalar {
+        let distance = (self.center - origin).norm();
+        if self.unbounded || distance <= self.radius {
+            return 4.0 * std::f32::consts::PI;
+        }
+        let cos_max = (1.0 - (self.radius / distance).powi(2)).max(0.0).sqrt();
+        std::f32::consts::TAU * (1.0 - cos_max)
+    }
+
+    /// Picks a unit direction of the light of `spot` towards the target,
+    /// uniformly. In unbounded scenes, it is within the cone of the spot
+    /// instead. Returns the direction and its solid angle density.
+    fn spot_direction(&self, spot: &SpotLight, u: (Scalar, Scalar)) -> (Vector, Scalar) {
+        if !self.unbounded {
+            return self.direction_from(&spot.position, u);
+        }
+        let cos_max = spot.angle.to_radians().cos();
+        let axis = spot.direction.normalize();
+        let dir = Frame::new(&axis).to_world(&uniform_sample_cone(u.0, u.1, cos_max));
+        (dir, 1.0 / self.spot_solid_angle(spot))
+    }
+
+    fn spot_solid_angle(&self, spot: &SpotLight) -> Scalar {
+        if !self.unbounded {
+            return self.solid_angle_from(&spot.position);
+        }
+        std::f32::consts::TAU * (1.0 - spot.angle.to_radians().cos())
+    }
+
+    /// Radius of the disk the parallel rays cross
+    fn disk_radius(&self) -> Scalar {
+        if self.unbounded {
+            UNBOUNDED_DISK * self.radius
+        } else {
+            self.radius
+        }
+    }
+
+    /// Ray travelling along the unit `dir` through a uniform point of the
+    /// disk covering the target, starting `distance` before it
+    fn parallel_ray(&self, dir: &Vector, distance: Scalar, u: (Scalar, Scalar)) -> Ray {
+        let (a, b) = orthonormal_basis(dir);
+        let p = self.disk_radius() * concentric_sample_disk(u.0, u.1);
+        Ray::new(self.center + p.x * a + p.y * b - distance * dir, *dir)
+    }
+
+    fn disk_area(&self) -> Scalar {
+        std::f32::consts::PI * self.disk_radius() * self.disk_radius()
+    }
+}
+
+fn luminance(c: &Color) -> Scalar {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/// Photon map of the caustics: light reflected or refracted by specular
+/// materials onto diffuse ones
+pub struct PhotonMap {
+    /// Balanced kd-tree: the node of a slice is its middle element, and its
+    /// children are the two halves around it
+    photons: Vec<Photon>,
+    max_radius: Scalar,
+}
+
+#[derive(PartialEq)]
+struct Neighbour {
+    distance2: Scalar,
+    index: usize,
+}
+
+impl Eq for Neighbour {}
+
+impl PartialOrd for Neighbour {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbour {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance2.total_cmp(&other.distance2)
+    }
+}
+
+/// Light sources of a world, picked by their power towards a target
+struct Sources<'a> {
+    world: &'a World,
+    target: Target,
+    /// Parallel rays start this far before the target, outside of the
+    /// bounded entities
+    back_off: Scalar,
+    sources: Vec<(Source<'a>, Scalar)>,
+    /// Cumulative probabilities of the sources
+    cdf: Vec<Scalar>,
+}
+
+impl<'a> Sources<'a> {
+    /// None if no light reaches the target
+    fn new(world: &'a World, target: Target, seed: u64) -> Option<Self> {
+        let scene_radius = world
+            .bounds()
+            .map_or(target.radius, |aabb| aabb.half_extents().norm());
+        let back_off = 2.0 * (scene_radius + (target.center - Point::origin()).norm());
+
+        let mut sources: Vec<(Source, Scalar)> = Vec::new();
+        for light in world.lights() {
+            let power = match light {
+                Light::Point {
+                    position,
+                    intensity,
+                } => luminance(intensity) * target.solid_angle_from(position),
+                Light::Spot(spot) => luminance(&spot.intensity) * target.spot_solid_angle(spot),
+                Light::Directional { intensity, .. } => luminance(intensity) * target.disk_area(),
+            };
+            sources.push((Source::Light(light), power));
+        }
+        for emitter in world.emitters() {
+            let emit = emitter.material().emitted(
+                &Ray::new(Point::origin(), Vector::z()),
+                &RayIntersection::new(0.0, Vector::z(), FeatureId::Unknown),
+            );
+            let center = emitter.aabb().center();
+            let power =
+                luminance(&emit) * emitter.area().unwrap_or(0.0) * target.solid_angle_from(&center);
+            sources.push((Source::Emitter(emitter), power));
+        }
+        let background = world.background();
+        let mut rng = sample_rng(seed, PHOTON_STREAM, 0);
+        let radiance = (0..256)
+            .map(|_| {
+                let dir = uniform_sample_sphere(rng.gen(), rng.gen());
+                luminance(&background.color(&dir))
+            })
+            .sum::<Scalar>()
+            / 256.0;
+        sources.push((
+            Source::Background,
+            radiance * 4.0 * std::f32::consts::PI * target.disk_area(),
+        ));
+
+        let total: Scalar = sources.iter().map(|(_, power)| power).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut cdf = Vec::with_capacity(sources.len());
+        let mut sum = 0.0;
+        for (_, power) in sources.iter_mut() {
+            *power /= total;
+            sum += *power;
+            cdf.push(sum);
+        }
+        Some(Sources {
+            world,
+            target,
+            back_off,
+            sources,
+            cdf,
+        })
+    }
+
+    /// Starts a photon from a source picked by its power. Returns its ray
+    /// and the power it carries, divided by the probability of the source.
+    fn emit(&self, rng: &mut Prng) -> Option<(Ray, Color)> {
+        let u: Scalar = rng.gen();
+        let index = self
+            .cdf
+            .partition_point(|p| *p <= u)
+            .min(self.sources.len() - 1);
+        let (source, probability) = &self.sources[index];
+        let (ray, power) = emit(rng, source, &self.target, self.back_off, self.world)?;
+        Some((ray, power / *probability))
+    }
+}
+
+impl PhotonMap {
+    /// Shoots `count` photons towards the specular entities, following them
+    /// through at most `max_depth` specular bounces. None if the world has no
+    /// specular entity to make caustics.
+    pub fn caustics(world: &World, count: usize, max_depth: usize, seed: u64) -> Option<Self> {
+        let sources = Sources::new(world, Target::new(&world.specular_bounds()?), seed)?;
+        let photons = (0..count)
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let mut rng = sample_rng(seed, PHOTON_STREAM, i as u64 + 1);
+                sources.emit(&mut rng).and_then(|(ray, power)| {
+                    trace_caustic(&mut rng, ray, power / count as Scalar, max_depth, world)
+                })
+            })
+            .collect();
+        Some(PhotonMap::new(photons, MAX_RADIUS * sources.target.radius))
+    }
+
+    /// Shoots `count` photons towards the bounded entities, or everywhere if
+    /// some are unbounded, storing them at every diffuse surface they bounce
+    /// off, but the first one. None if no light reaches the entities.
+    pub fn global(world: &World, count: usize, max_depth: usize, seed: u64) -> Option<Self> {
+        let sources = Sources::new(world, Target::world(world)?, seed)?;
+        let photons = (0..count)
+            .into_par_iter()
+            .flat_map_iter(|i| {
+                let mut rng = sample_rng(seed, PHOTON_STREAM, i as u64 + 1);
+                sources
+                    .emit(&mut rng)
+                    .map(|(ray, power)| {
+                        trace_global(&mut rng, ray, power / count as Scalar, max_depth, world)
+                    })
+                    .unwrap_or_default()
+            })
+            .collect();
+        Some(PhotonMap::new(photons, MAX_RADIUS * sources.target.radius))
+    }
+
+    fn new(mut photons: Vec<Photon>, max_radius: Scalar) -> Self {
+        build(&mut photons);
+        PhotonMap {
+            photons,
+            max_radius,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Caustic light leaving `point` towards `wo`, in the shading `frame` of
+    /// the diffuse `material`, estimated from the density of the photons
+    /// around
+    pub fn radiance(
+        &self,
+        point: &Point,
+        frame: &Frame,
+        wo: &Vector,
+        material: &MaterialKind,
+    ) -> Color {
+        let mut heap = BinaryHeap::with_capacity(NEAREST_PHOTONS + 1);
+        let mut max_distance2 = self.max_radius * self.max_radius;
+        self.nearest(0, self.photons.len(), point, &mut heap, &mut max_distance2);
+        if heap.is_empty() {
+            return Color::zeros();
+        }
+        // The disk holding the photons, which is the whole search disk when
+        // there are too few of them: a lone photon is not a bright spot
+        let area = std::f32::consts::PI * max_distance2;
+        let color: Color = heap
+            .iter()
+            .map(|neighbour| self.photons[neighbour.index].reflected(frame, wo, material))
+            .sum();
+        color / area
+    }
+
+    /// Power of the photons within `radius` of `point` reflected towards
+    /// `wo`, in the shading `frame` of the diffuse `material`, and the number
+    /// of these photons. Only the photons on the side of `frame` count
+    pub fn gather(
+        &self,
+        point: &Point,
+        radius: Scalar,
+        frame: &Frame,
+        wo: &Vector,
+        material: &MaterialKind,
+    ) -> (Color, usize) {
+        let mut color = Color::zeros();
+        let mut count = 0;
+        self.within(
+            0,
+            self.photons.len(),
+            point,
+            radius * radius,
+            &mut |photon| {
+                if photon.same_side(frame, wo) {
+                    color += photon.reflected(frame, wo, material);
+                    count += 1;
+                }
+            },
+        );
+        (color, count)
+    }
+
+    /// Calls `f` on the photons of the subtree `lo..hi` within
+    /// `distance2` of `point`
+    fn within(
+        &self,
+        lo: usize,
+        hi: usize,
+        point: &Point,
+        distance2: Scalar,
+        f: &mut impl FnMut(&Photon),
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let axis = photon.axis as usize;
+        let delta = point[axis] - photon.position[axis];
+        if delta < 0.0 || delta * delta < distance2 {
+            self.within(lo, mid, point, distance2, f);
+        }
+        if (photon.position - point).norm_squared() < distance2 {
+            f(photon);
+        }
+        if delta >= 0.0 || delta * delta < distance2 {
+            self.within(mid + 1, hi, point, distance2, f);
+        }
+    }
+
+    /// Keeps the `NEAREST_PHOTONS` closest to `point` in `heap`, among the
+    /// ones of the subtree `lo..hi` within `max_distance2`, which shrinks to
+    /// the farthest one once the heap is full
+    fn nearest(
+        &self,
+        lo: usize,
+        hi: usize,
+        point: &Point,
+        heap: &mut BinaryHeap<Neighbour>,
+        max_distance2: &mut Scalar,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let axis = photon.axis as usize;
+        let delta = point[axis] - photon.position[axis];
+        let (near, far) = if delta < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.nearest(near.0, near.1, point, heap, max_distance2);
+        let distance2 = (photon.position - point).norm_squared();
+        if distance2 < *max_distance2 {
+            heap.push(Neighbour {
+                distance2,
+                index: mid,
+            });
+            if heap.len() > NEAREST_PHOTONS {
+                heap.pop();
+            }
+            if heap.len() == NEAREST_PHOTONS {
+                *max_distance2 = heap.peek().unwrap().distance2;
+            }
+        }
+        if delta * delta < *max_distance2 {
+            self.nearest(far.0, far.1, point, heap, max_distance2);
+        }
+    }
+}
+
+/// Starts a photon from `source`, aimed at the target. Returns its ray and
+/// the power it carries, before dividing by the number of photons and the
+/// probability of the source.
+fn emit(
+    rng: &mut Prng,
+    source: &Source,
+    target: &Target,
+    back_off: Scalar,
+    world: &World,
+) -> Option<(Ray, Color)> {
+    match source {
+        Source::Light(Light::Point {
+            position,
+            intensity,
+        }) => {
+            let (dir, pdf) = target.direction_from(position, (rng.gen(), rng.gen()));
+            Some((Ray::new(*position, dir), intensity / pdf))
+        }
+        Source::Light(Light::Spot(spot)) => {
+            let (dir, pdf) = target.spot_direction(spot, (rng.gen(), rng.gen()));
+            let attenuation = spot.attenuation(&dir);
+            (attenuation > 0.0).then(|| {
+                (
+                    Ray::new(spot.position, dir),
+                    spot.intensity * attenuation / pdf,
+                )
+            })
+        }
+        Source::Light(Light::Directional {
+            direction,
+            intensity,
+        }) => {
+            let dir = direction.normalize();
+            let ray = target.parallel_ray(&dir, back_off, (rng.gen(), rng.gen()));
+            Some((ray, intensity * target.disk_area()))
+        }
+        Source::Emitter(emitter) => {
+            let (point, normal) = emitter.sample((rng.gen(), rng.gen()))?;
+            let (dir, pdf) = target.direction_from(&point, (rng.gen(), rng.gen()));
+            let cos_theta = normal.dot(&dir);
+            if emitter.one_sided() && cos_theta <= 0.0 {
+                return None;
+            }
+            let ray = Ray::new(point, dir);
+            let intersection = RayIntersection::new(0.0, normal, FeatureId::Unknown);
+            let emit = emitter.material().emitted(&ray, &intersection);
+            let area = emitter.area()?;
+            Some((ray, emit * cos_theta.abs() * area / pdf))
+        }
+        Source::Background => {
+            // Environment maps are importance sampled, the other backgrounds
+            // are smooth enough to be sampled uniformly
+            let background = world.background();
+            let (dir, radiance, pdf) = match background {
+                Background::Environment(_) => background.sample(rng.gen(), rng.gen())?,
+                _ => {
+                    let dir = uniform_sample_sphere(rng.gen(), rng.gen());
+                    let pdf = 1.0 / (4.0 * std::f32::consts::PI);
+                    (dir, background.color(&dir), pdf)
+                }
+            };
+            let ray = target.parallel_ray(&-dir, back_off, (rng.gen(), rng.gen()));
+            Some((ray, radiance * target.disk_area() / pdf))
+        }
+    }
+}
+
+/// Follows a photon through specular bounces, up to the diffuse surface it
+/// is stored on
+fn trace_caustic(
+    rng: &mut Prng,
+    mut ray: Ray,
+    mut power: Color,
+    max_depth: usize,
+    world: &World,
+) -> Option<Photon> {
+    for depth in 0..max_depth {
+        let hit = world.hit(&ray)?;
+        let flags = hit.material.flags();
+        let dir = ray.dir.normalize();
+        if flags.intersects(BsdfFlags::DIFFUSE) {
+            // Photons straight from the light are direct lighting, not
+            // caustics
+            return (depth > 0).then(|| Photon {
+                position: ray.point_at(hit.intersection.toi),
+                wi: -dir,
+                power,
+                axis: 0,
+            });
+        }
+        if !flags.intersects(BsdfFlags::SPECULAR) {
+            return None;
+        }
+        // Caustics off unbounded entities are left to the camera paths,
+        // which find them by scattering: storing them would count them twice
+        if depth == 0 && !world.is_bounded(&hit) {
+            return None;
+        }
+        let frame = Frame::new(&hit.intersection.normal);
+        let sample = hit
+            .material
+            .sample(&frame.to_local(&-dir), (rng.gen(), rng.gen()))?;
+        power = power.component_mul(&sample.weight());
+        ray = Ray::new(
+            ray.point_at(hit.intersection.toi),
+            frame.to_world(&sample.wi),
+        );
+    }
+    None
+}
+
+/// Arranges `photons` as a balanced kd-tree, splitting along the largest
+/// extent of each subtree
+fn build(photons: &mut [Photon]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let aabb = Aabb::from_points(photons.iter().map(|photon| &photon.position));
+    let extents = aabb.extents();
+    let axis = extents.imax();
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.position[axis].total_cmp(&b.position[axis]));
+    photons[mid].axis = axis as u8;
+    let (left, right) = photons.split_at_mut(mid);
+    build(left);
+    build(&mut right[1..]);
+}
+
+/// Follows a photon as it bounces around, storing it on the diffuse surfaces
+/// it meets after leaving the light
+fn trace_global(
+    rng: &mut Prng,
+    mut ray: Ray,
+    mut power: Color,
+    max_depth: usize,
+    world: &World,
+) -> Vec<Photon> {
+    let mut photons = Vec::new();
+    for depth in 0..max_depth {
+        let Some(hit) = world.hit(&ray) else {
+            break;
+        };
+        let dir = ray.dir.normalize();
+        let point = ray.point_at(hit.intersection.toi);
+        // Photons straight from the light are direct lighting, which the
+        // camera paths sample instead
+        if depth > 0 && hit.material.flags().intersects(BsdfFlags::DIFFUSE) {
+            photons.push(Photon {
+                position: point,
+                wi: -dir,
+                power,
+                axis: 0,
+            });
+        }
+        let frame = Frame::new(&hit.intersection.normal);
+        let Some(sample) = hit
+            .material
+            .sample(&frame.to_local(&-dir), (rng.gen(), rng.gen()))
+        else {
+            break;
+        };
+        // Russian roulette: photons survive with the fraction of their power
+        // left, so that the power of the survivors stays about the same
+        let scattered = power.component_mul(&sample.weight());
+        let survival = (luminance(&scattered) / luminance(&power)).min(1.0);
+        if survival.is_nan() || rng.gen::<Scalar>() >= survival {
+            break;
+        }
+        power = scattered / survival;
+        ray = Ray::new(point, frame.to_world(&sample.wi));
+    }
+    photons
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::material::Lambertian;
+
+    /// Photons clustered in a few blobs, some of them on the same position,
+    /// with their kd-tree
+    fn photon_map(count: usize) -> PhotonMap {
+        let mut rng = Prng::seed_from_u64(0);
+        let centers: Vec<Point> = (0..5)
+            .map(|_| Point::new(rng.gen(), rng.gen(), rng.gen()))
+            .collect();
+        let photons = (0..count)
+            .map(|i| {
+                let center = centers[i % centers.len()];
+                let offset = if i % 7 == 0 {
+                    Vector::zeros()
+                } else {
+                    0.2 * Vector::new(rng.gen(), rng.gen(), rng.gen())
+                };
+                Photon {
+                    position: center + offset,
+                    wi: Vector::y(),
+                    power: Color::new(1.0, 1.0, 1.0),
+                    axis: 0,
+                }
+            })
+            .collect();
+        PhotonMap::new(photons, 0.1)
+    }
+
+    /// Query points: random ones, mostly away from the photons, and some of
+    /// the photons
+    fn queries(map: &PhotonMap) -> Vec<Point> {
+        let mut rng = Prng::seed_from_u64(1);
+        let mut points: Vec<Point> = (0..200)
+            .map(|_| Point::new(rng.gen(), rng.gen(), rng.gen()) * 1.4 - Vector::repeat(0.2))
+            .collect();
+        points.extend(map.photons.iter().step_by(10).map(|photon| photon.position));
+        points
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut full = 0;
+        for count in [0, 1, 2, 30, 1000] {
+            let map = photon_map(count);
+            for point in queries(&map) {
+                let max_distance2 = 0.01;
+                let mut heap = BinaryHeap::new();
+                let mut found_distance2 = max_distance2;
+                map.nearest(0, count, &point, &mut heap, &mut found_distance2);
+                let mut found: Vec<Scalar> = heap.iter().map(|n| n.distance2).collect();
+                found.sort_by(Scalar::total_cmp);
+
+                let mut expected: Vec<Scalar> = map
+                    .photons
+                    .iter()
+                    .map(|photon| (photon.position - point).norm_squared())
+                    .filter(|distance2| *distance2 < max_distance2)
+                    .collect();
+                expected.sort_by(Scalar::total_cmp);
+                expected.truncate(NEAREST_PHOTONS);
+                // Ties are broken arbitrarily: compare the distances only
+                assert_eq!(found, expected);
+                if expected.len() == NEAREST_PHOTONS {
+                    assert_eq!(found_distance2, expected[NEAREST_PHOTONS - 1]);
+                    full += 1;
+                }
+            }
+        }
+        assert!(full > 10, "the search radius never shrank");
+    }
+
+    #[test]
+    fn within_matches_brute_force() {
+        for count in [0, 1, 2, 30, 1000] {
+            let map = photon_map(count);
+            for point in queries(&map) {
+                for radius in [0.0, 0.01, 0.05, 0.3] {
+                    let distance2 = radius * radius;
+                    let mut found = Vec::new();
+                    map.within(0, count, &point, distance2, &mut |photon| {
+                        found.push((photon.position - point).norm_squared())
+                    });
+                    found.sort_by(Scalar::total_cmp);
+                    let mut expected: Vec<Scalar> = map
+                        .photons
+                        .iter()
+                        .map(|photon| (photon.position - point).norm_squared())
+                        .filter(|d2| *d2 < distance2)
+                        .collect();
+                    expected.sort_by(Scalar::total_cmp);
+                    assert_eq!(found, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gather_skips_the_other_side() {
+        let photon = |wi: Vector| Photon {
+            position: Point::origin(),
+            wi,
+            power: Color::new(1.0, 1.0, 1.0),
+            axis: 0,
+        };
+        let photons = vec![
+            photon(Vector::y()),
+            photon(-Vector::y()),
+            photon(Vector::y()),
+        ];
+        let map = PhotonMap::new(photons, 1.0);
+        let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let frame = Frame::new(&Vector::y());
+        let (color, count) = map.gather(&Point::origin(), 0.1, &frame, &Vector::z(), &material);
+        assert_eq!(count, 2);
+        let f = material.eval(&Vector::z(), &Vector::z());
+        assert!((color - 2.0 * f).norm() < 1e-6);
+        // Seen from the other side of the wall
+        let (_, count) = map.gather(&Point::origin(), 0.1, &frame, &-Vector::z(), &material);
+        assert_eq!(count, 1);
+    }
+}
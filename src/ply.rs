@@ -0,0 +1,447 @@
+//! Stanford PLY loader, ASCII and binary
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::Mesh;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Type {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl Type {
+    fn parse(name: &str) -> Result<Type> {
+        Ok(match name {
+            "char" | "int8" => Type::Int8,
+            "uchar" | "uint8" => Type::UInt8,
+            "short" | "int16" => Type::Int16,
+            "ushort" | "uint16" => Type::UInt16,
+            "int" | "int32" => Type::Int32,
+            "uint" | "uint32" => Type::UInt32,
+            "float" | "float32" => Type::Float32,
+            "double" | "float64" => Type::Float64,
+            _ => bail!("Unknown property type '{}'", name),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Type::Int8 | Type::UInt8 => 1,
+            Type::Int16 | Type::UInt16 => 2,
+            Type::Int32 | Type::UInt32 | Type::Float32 => 4,
+            Type::Float64 => 8,
+        }
+    }
+
+    /// Scale mapping the values of the type to [0, 1], for colors
+    fn color_scale(&self) -> f64 {
+        match self {
+            Type::UInt8 => 255.0,
+            Type::UInt16 => 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+enum Property {
+    Scalar(Type),
+    /// Type of the length, type of the items
+    List(Type, Type),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Property)>,
+}
+
+impl Element {
+    fn property(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|(name, _)| names.contains(&name.as_str()))
+    }
+}
+
+/// Reads the values of the body, whatever the format
+struct Reader<'a> {
+    format: Format,
+    body: &'a [u8],
+    offset: usize,
+    /// Current line of the ASCII body, for error messages
+    line: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn ascii_token(&mut self) -> Result<&'a str> {
+        while self.offset < self.body.len() && self.body[self.offset].is_ascii_whitespace() {
+            if self.body[self.offset] == b'\n' {
+                self.line += 1;
+            }
+            self.offset += 1;
+        }
+        let start = self.offset;
+        while self.offset < self.body.len() && !self.body[self.offset].is_ascii_whitespace() {
+            self.offset += 1;
+        }
+        if start == self.offset {
+            bail!("Unexpected end of file");
+        }
+        std::str::from_utf8(&self.body[start..self.offset]).context("Invalid number")
+    }
+
+    fn read(&mut self, ty: Type) -> Result<f64> {
+        if self.format == Format::Ascii {
+            let token = self.ascii_token()?;
+            return token
+                .parse()
+                .with_context(|| format!("Invalid number '{}'", token));
+        }
+        let size = ty.size();
+        let Some(bytes) = self.body.get(self.offset..self.offset + size) else {
+            bail!("Unexpected end of file");
+        };
+        self.offset += size;
+        let mut buffer = [0u8; 8];
+        buffer[..size].copy_from_slice(bytes);
+        if self.format == Format::BinaryBigEndian {
+            buffer[..size].reverse();
+        }
+        let value = match ty {
+            Type::Int8 => buffer[0] as i8 as f64,
+            Type::UInt8 => buffer[0] as f64,
+            Type::Int16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            Type::UInt16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            Type::Int32 => i32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            Type::UInt32 => u32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            Type::Float32 => f32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            Type::Float64 => f64::from_le_bytes(buffer),
+        };
+        Ok(value)
+    }
+
+    /// Values of all the properties of an element, lists flattened
+    fn element(&mut self, element: &Element, values: &mut Vec<Vec<f64>>) -> Result<()> {
+        values.resize(element.properties.len(), Vec::new());
+        for ((_, property), value) in element.properties.iter().zip(values.iter_mut()) {
+            value.clear();
+            match property {
+                Property::Scalar(ty) => value.push(self.read(*ty)?),
+                Property::List(len_ty, ty) => {
+                    let len = self.read(*len_ty)? as usize;
+                    for _ in 0..len {
+                        value.push(self.read(*ty)?);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header_line(line: &str, format: &mut Option<Format>, elements: &mut Vec<Element>) -> Result<()> {
+    let mut tokens = line.split_whitespace();
+    match tokens.next() {
+        Some("format") => {
+            *format = Some(match tokens.next() {
+                Some("ascii") => Format::Ascii,
+                Some("binary_little_endian") => Format::BinaryLittleEndian,
+                Some("binary_big_endian") => Format::BinaryBigEndian,
+                _ => bail!("Unknown format '{}'", line),
+            })
+        }
+        Some("element") => {
+            let (Some(name), Some(count)) = (tokens.next(), tokens.next()) else {
+                bail!("Invalid element '{}'", line);
+            };
+            elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().context("Invalid element count")?,
+                properties: Vec::new(),
+            });
+        }
+        Some("property") => {
+            let Some(element) = elements.last_mut() else {
+                bail!("Property outside of an element");
+            };
+            let tokens: Vec<&str> = tokens.collect();
+            let (property, name) = match tokens[..] {
+                ["list", len_ty, ty, name] => {
+                    (Property::List(Type::parse(len_ty)?, Type::parse(ty)?), name)
+                }
+                [ty, name] => (Property::Scalar(Type::parse(ty)?), name),
+                _ => bail!("Invalid property '{}'", line),
+            };
+            element.properties.push((name.to_string(), property));
+        }
+        // Comments, object information, empty lines
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Parses the header, returns the format, the elements and the offset of
+/// the body
+fn header(path: &Path, content: &[u8]) -> Result<(Format, Vec<Element>, usize)> {
+    if !content.starts_with(b"ply") {
+        bail!("{}: not a PLY file", path.display());
+    }
+    let mut format = None;
+    let mut elements = Vec::new();
+    let mut offset = 0;
+    for line_nr in 1.. {
+        let Some(end) = content[offset..].iter().position(|c| *c == b'\n') else {
+            bail!("{}: missing 'end_header'", path.display());
+        };
+        let line = String::from_utf8_lossy(&content[offset..offset + end]);
+        offset += end + 1;
+        header_line(&line, &mut format, &mut elements)
+            .with_context(|| format!("{}:{}", path.display(), line_nr))?;
+        if line.trim() == "end_header" {
+            break;
+        }
+    }
+    let format = format.with_context(|| format!("{}: missing format", path.display()))?;
+    Ok((format, elements, offset))
+}
+
+/// Loads a PLY file. Its faces are triangulated, and its vertex colors, if
+/// any, are used as the albedo of `material`. Vertex colors are assumed gamma
+/// encoded, like the rendered images. Without `material`, vertex colors are
+/// required, and used as the albedo of a `Lambertian` material.
+pub fn load(path: &Path, material: Option<&MaterialKind>) -> Result<Mesh> {
+    let content = fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let (format, elements, offset) = header(path, &content)?;
+
+    let mut reader = Reader {
+        format,
+        body: &content[offset..],
+        offset: 0,
+        line: 1,
+    };
+    let mut vertices = Vec::new();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+    let mut indices = Vec::new();
+    let mut values = Vec::new();
+    let body_line = offset_line(&content, offset);
+    for element in &elements {
+        let position = ["x", "y", "z"].map(|n| element.property(&[n]));
+        let normal = ["nx", "ny", "nz"].map(|n| element.property(&[n]));
+        let color =
+            [&["red", "r"][..], &["green", "g"], &["blue", "b"]].map(|n| element.property(n));
+        let face = element.property(&["vertex_indices", "vertex_index"]);
+
+        for i in 0..element.count {
+            reader
+                .element(element, &mut values)
+                .with_context(|| match format {
+                    Format::Ascii => format!("{}:{}", path.display(), body_line + reader.line - 1),
+                    _ => format!("{}: {} {}", path.display(), element.name, i),
+                })?;
+            match element.name.as_str() {
+                "vertex" => {
+                    let [Some(x), Some(y), Some(z)] = position else {
+                        bail!("{}: vertices without position", path.display());
+                    };
+                    vertices.push(Point::new(
+                        values[x][0] as Scalar,
+                        values[y][0] as Scalar,
+                        values[z][0] as Scalar,
+                    ));
+                    if let [Some(x), Some(y), Some(z)] = normal {
+                        normals.push(Vector::new(
+                            values[x][0] as Scalar,
+                            values[y][0] as Scalar,
+                            values[z][0] as Scalar,
+                        ));
+                    }
+                    if let [Some(r), Some(g), Some(b)] = color {
+                        let channel = |p: usize| {
+                            let Property::Scalar(ty) = element.properties[p].1 else {
+                                return 0.0;
+                            };
+                            let c = (values[p][0] / ty.color_scale()) as Scalar;
+                            c * c
+                        };
+                        colors.push(Color::new(channel(r), channel(g), channel(b)));
+                    }
+                }
+                "face" => {
+                    let Some(face) = face else {
+                        bail!("{}: faces without vertex indices", path.display());
+                    };
+                    let corners = &values[face];
+                    if corners.len() < 3 {
+                        bail!(
+                            "{}: face {} has {} vertices",
+                            path.display(),
+                            i,
+                            corners.len()
+                        );
+                    }
+                    // Polygons are triangulated as a fan
+                    for k in 1..corners.len() - 1 {
+                        indices.push([corners[0], corners[k], corners[k + 1]].map(|c| c as u32));
+                    }
+                }
+                // Edges, materials, ... are skipped
+                _ => {}
+            }
+        }
+    }
+
+    let has_colors = !colors.is_empty();
+    let material = match material {
+        Some(material) => material.clone(),
+        None if has_colors => MaterialKind::Lambertian(Lambertian::new(Color::new(1.0, 1.0, 1.0))),
+        None => bail!("{}: no material and no vertex colors", path.display()),
+    };
+    let normals = (!normals.is_empty()).then_some(normals);
+    let mut mesh = Mesh::new(vertices, indices, normals, material)
+        .with_context(|| format!("Invalid mesh '{}'", path.display()))?;
+    if has_colors {
+        mesh = mesh.with_colors(colors)?;
+    }
+    Ok(mesh)
+}
+
+/// Line number of `offset` in `content`
+fn offset_line(content: &[u8], offset: usize) -> usize {
+    1 + content[..offset].iter().filter(|c| **c == b'\n').count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "ply
+format {} 1.0
+comment a square
+element vertex 4
+property float x
+property float y
+property double z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    const VERTICES: [[f64; 6]; 4] = [
+        [0.0, 0.0, -1.5, 255.0, 0.0, 0.0],
+        [1.0, 0.0, 0.25, 0.0, 255.0, 0.0],
+        [1.0, 1.0, 0.0, 0.0, 0.0, 255.0],
+        [0.0, 1.0, 1e10, 128.0, 128.0, 128.0],
+    ];
+
+    fn file(format: &str) -> Vec<u8> {
+        let mut content = HEADER.replace("{}", format).into_bytes();
+        let big = format == "binary_big_endian";
+        macro_rules! push {
+            ($value:expr) => {
+                if big {
+                    content.extend($value.to_be_bytes())
+                } else {
+                    content.extend($value.to_le_bytes())
+                }
+            };
+        }
+        for [x, y, z, r, g, b] in VERTICES {
+            if format == "ascii" {
+                content.extend(format!("{} {} {} {} {} {}\n", x, y, z, r, g, b).bytes());
+            } else {
+                push!(x as f32);
+                push!(y as f32);
+                push!(z);
+                push!(r as u8);
+                push!(g as u8);
+                push!(b as u8);
+            }
+        }
+        if format == "ascii" {
+            content.extend(b"4 0 1 2 3\n");
+        } else {
+            push!(4u8);
+            for i in 0..4i32 {
+                push!(i);
+            }
+        }
+        content
+    }
+
+    /// Values of every element of the file
+    fn read(content: &[u8]) -> Result<(Format, Vec<Vec<Vec<f64>>>)> {
+        let (format, elements, offset) = header(Path::new("test.ply"), content)?;
+        let mut reader = Reader {
+            format,
+            body: &content[offset..],
+            offset: 0,
+            line: 1,
+        };
+        let mut values = Vec::new();
+        for element in &elements {
+            for _ in 0..element.count {
+                let mut element_values = Vec::new();
+                reader.element(element, &mut element_values)?;
+                values.push(element_values);
+            }
+        }
+        Ok((format, values))
+    }
+
+    #[test]
+    fn formats_read_the_same_values() {
+        let (format, ascii) = read(&file("ascii")).unwrap();
+        assert!(format == Format::Ascii);
+        assert_eq!(ascii.len(), 5);
+        for (values, expected) in ascii.iter().zip(VERTICES) {
+            let values: Vec<f64> = values.iter().map(|v| v[0]).collect();
+            assert_eq!(values, expected);
+        }
+        assert_eq!(ascii[4], [vec![0.0, 1.0, 2.0, 3.0]]);
+
+        let (format, little) = read(&file("binary_little_endian")).unwrap();
+        assert!(format == Format::BinaryLittleEndian);
+        let (format, big) = read(&file("binary_big_endian")).unwrap();
+        assert!(format == Format::BinaryBigEndian);
+        assert_eq!(little, ascii);
+        assert_eq!(big, ascii);
+    }
+
+    #[test]
+    fn truncated_files() {
+        let content = file("binary_little_endian");
+        assert!(read(&content[..content.len() - 1]).is_err());
+        let content = file("ascii");
+        assert!(read(&content[..content.len() - 3]).is_err());
+        let content = file("binary_big_endian");
+        let end = content
+            .windows(10)
+            .position(|w| w == b"end_header")
+            .unwrap();
+        assert!(read(&content[..end]).is_err());
+        assert!(read(b"solid\n").is_err());
+    }
+}
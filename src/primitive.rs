@@ -0,0 +1,283 @@
+use parry3d::bounding_volume::{Aabb, BoundingVolume};
+use parry3d::query::{Ray, RayCast, RayIntersection};
+use parry3d::shape::{FeatureId, HalfSpace, Shape};
+
+use crate::material::*;
+use crate::math::*;
+use crate::world::Entity;
+
+/// Convex parry shape (cuboid, cylinder, cone, capsule, ...) placed in the
+/// world
+pub struct Convex<S> {
+    shape: S,
+    isometry: Isometry,
+    material: MaterialKind,
+}
+
+impl<S: Shape> Convex<S> {
+    pub fn new(shape: S, isometry: Isometry, material: MaterialKind) -> Self {
+        Convex {
+            shape,
+            isometry,
+            material,
+        }
+    }
+}
+
+impl<S: Shape> Entity for Convex<S> {
+    fn material(&self) -> &MaterialKind {
+        &self.material
+    }
+
+    fn aabb(&self) -> Aabb {
+        self.shape.compute_aabb(&self.isometry)
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        let shifted = Ray::new(ray.point_at(t_min), ray.dir);
+        let local = shifted.inverse_transform_by(&self.isometry);
+        let mut intersection =
+            self.shape
+                .cast_local_ray_and_get_normal(&local, t_max - t_min, false)?;
+        intersection.toi += t_min;
+
+        // The shape is convex: a ray starting inside leaves it, one starting
+        // outside enters it. Orient the normal outward accordingly.
+        let leaving = self.shape.contains_local_point(&local.origin);
+        let normal = self.isometry * intersection.normal;
+        intersection.normal = if (normal.dot(&ray.dir) > 0.0) == leaving {
+            normal
+        } else {
+            -normal
+        };
+        Some(intersection)
+    }
+}
+
+/// Infinite plane, facing the side its normal points to
+pub struct Plane {
+    half_space: HalfSpace,
+    isometry: Isometry,
+    material: MaterialKind,
+}
+
+impl Plane {
+    pub fn new(point: Point, normal: Vector, material: MaterialKind) -> Self {
+        Plane {
+            half_space: HalfSpace::new(nalgebra::Unit::new_normalize(normal)),
+            isometry: point.into(),
+            material,
+        }
+    }
+}
+
+impl Entity for Plane {
+    fn material(&self) -> &MaterialKind {
+        &self.material
+    }
+
+    fn aabb(&self) -> Aabb {
+        let infinity = Point::new(Scalar::INFINITY, Scalar::INFINITY, Scalar::INFINITY);
+        Aabb::new(-infinity, infinity)
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        let shifted = Ray::new(ray.point_at(t_min), ray.dir);
+        let local = shifted.inverse_transform_by(&self.isometry);
+        let mut intersection =
+            self.half_space
+                .cast_local_ray_and_get_normal(&local, t_max - t_min, false)?;
+        intersection.toi += t_min;
+        intersection.normal = *self.half_space.normal;
+        Some(intersection)
+    }
+}
+
+/// Intersection of `ray` with the plane going through `point` with the unit
+/// `normal`, as a time of impact in [`t_min`, `t_max`]
+fn plane_toi(
+    point: &Point,
+    normal: &Vector,
+    ray: &Ray,
+    t_min: Scalar,
+    t_max: Scalar,
+) -> Option<Scalar> {
+    let denom = normal.dot(&ray.dir);
+    if denom.abs() < 1e-8 {
+        return None;
+    }
+    let toi = normal.dot(&(point - ray.origin)) / denom;
+    (t_min..=t_max).contains(&toi).then_some(toi)
+}
+
+/// Bounding box of planar points, slightly padded so that it is never flat
+fn planar_aabb(points: &[Point]) -> Aabb {
+    let mut aabb = Aabb::from_points(points);
+    aabb.loosen(1e-4);
+    aabb
+}
+
+/// Parallelogram spanned by the edges `u` and `v` from `corner`
+pub struct Quad {
+    corner: Point,
+    u: Vector,
+    v: Vector,
+    normal: Vector,
+    /// Maps a point of the plane to its (u, v) coordinates
+    w: Vector,
+    one_sided: bool,
+    material: MaterialKind,
+}
+
+impl Quad {
+    /// The quad faces the side of `u` x `v`. If `one_sided`, only that side
+    /// emits light.
+    pub fn new(
+        corner: Point,
+        u: Vector,
+        v: Vector,
+        one_sided: bool,
+        material: MaterialKind,
+    ) -> Self {
+        let n = u.cross(&v);
+        Quad {
+            corner,
+            u,
+            v,
+            normal: n.normalize(),
+            w: n / n.dot(&n),
+            one_sided,
+            material,
+        }
+    }
+
+    /// Coordinates of `point` along the edges, in [0, 1] inside the quad
+    pub fn uv(&self, point: &Point) -> [Scalar; 2] {
+        let p = point - self.corner;
+        [self.w.dot(&p.cross(&self.v)), self.w.dot(&self.u.cross(&p))]
+    }
+}
+
+impl Entity for Quad {
+    fn material(&self) -> &MaterialKind {
+        &self.material
+    }
+
+    fn aabb(&self) -> Aabb {
+        let c = self.corner;
+        planar_aabb(&[c, c + self.u, c + self.v, c + self.u + self.v])
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        let toi = plane_toi(&self.corner, &self.normal, ray, t_min, t_max)?;
+        let [a, b] = self.uv(&ray.point_at(toi));
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return None;
+        }
+        Some(RayIntersection::new(toi, self.normal, FeatureId::Face(0)))
+    }
+
+    fn uv_at(&self, ray: &Ray, intersection: &RayIntersection) -> Option<[Scalar; 2]> {
+        Some(self.uv(&ray.point_at(intersection.toi)))
+    }
+
+    fn one_sided(&self) -> bool {
+        self.one_sided
+    }
+
+    fn area(&self) -> Option<Scalar> {
+        Some(self.u.cross(&self.v).norm())
+    }
+
+    fn sample(&self, u: (Scalar, Scalar)) -> Option<(Point, Vector)> {
+        Some((self.corner + u.0 * self.u + u.1 * self.v, self.normal))
+    }
+}
+
+pub struct Disk {
+    center: Point,
+    normal: Vector,
+    radius: Scalar,
+    /// Origin of the angles, and the direction at a quarter turn
+    tangent: Vector,
+    bitangent: Vector,
+    one_sided: bool,
+    material: MaterialKind,
+}
+
+impl Disk {
+    /// The disk faces the side of `normal`. If `one_sided`, only that side
+    /// emits light.
+    pub fn new(
+        center: Point,
+        normal: Vector,
+        radius: Scalar,
+        one_sided: bool,
+        material: MaterialKind,
+    ) -> Self {
+        let normal = normal.normalize();
+        let (tangent, bitangent) = orthonormal_basis(&normal);
+        Disk {
+            center,
+            normal,
+            radius,
+            tangent,
+            bitangent,
+            one_sided,
+            material,
+        }
+    }
+
+    /// Polar coordinates of `point`: distance to the center relative to the
+    /// radius, and angle in turns
+    pub fn uv(&self, point: &Point) -> [Scalar; 2] {
+        let p = point - self.center;
+        let angle = p.dot(&self.bitangent).atan2(p.dot(&self.tangent));
+        [
+            p.norm() / self.radius,
+            angle.rem_euclid(std::f32::consts::TAU) / std::f32::consts::TAU,
+        ]
+    }
+}
+
+impl Entity for Disk {
+    fn material(&self) -> &MaterialKind {
+        &self.material
+    }
+
+    fn aabb(&self) -> Aabb {
+        // Extent of the disk along each axis
+        let extent = self
+            .normal
+            .map(|n| self.radius * (1.0 - n * n).max(0.0).sqrt());
+        planar_aabb(&[self.center - extent, self.center + extent])
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        let toi = plane_toi(&self.center, &self.normal, ray, t_min, t_max)?;
+        if (ray.point_at(toi) - self.center).norm_squared() > self.radius * self.radius {
+            return None;
+        }
+        Some(RayIntersection::new(toi, self.normal, FeatureId::Face(0)))
+    }
+
+    fn uv_at(&self, ray: &Ray, intersection: &RayIntersection) -> Option<[Scalar; 2]> {
+        Some(self.uv(&ray.point_at(intersection.toi)))
+    }
+
+    fn one_sided(&self) -> bool {
+        self.one_sided
+    }
+
+    fn area(&self) -> Option<Scalar> {
+        Some(std::f32::consts::PI * self.radius * self.radius)
+    }
+
+    fn sample(&self, u: (Scalar, Scalar)) -> Option<(Point, Vector)> {
+        let p = self.radius * concentric_sample_disk(u.0, u.1);
+        Some((
+            self.center + p.x * self.tangent + p.y * self.bitangent,
+            self.normal,
+        ))
+    }
+}
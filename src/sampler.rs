@@ -0,0 +1,189 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::math::{hash, Prng, Scalar};
+
+/// Strategy used to pick the 2D samples of a pixel (pixel jitter, lens, ...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Sampler {
+    /// Independent uniform random samples
+    #[default]
+    Random,
+    /// Jittered grid. Only the first floor(sqrt(spp))^2 samples are
+    /// stratified, the remaining ones are random
+    Stratified,
+    /// Halton sequence, rotated per pixel
+    Halton,
+    /// Sobol sequence, scrambled per pixel
+    Sobol,
+}
+
+/// Number of 2D dimensions supported by the low discrepancy sequences.
+/// Dimension 0 is the pixel jitter, dimension 1 the lens position.
+const DIMENSIONS: usize = 2;
+
+const HALTON_BASES: [[u32; 2]; DIMENSIONS] = [[2, 3], [5, 7]];
+
+impl Sampler {
+    /// Returns a sample in [0, 1)^2 for the `index`-th sample (out of
+    /// `samples_per_pixel`) of the pixel identified by `pixel_seed`, for the
+    /// 2D `dimension`.
+    pub fn sample_2d(
+        &self,
+        rng: &mut Prng,
+        pixel_seed: u64,
+        index: usize,
+        samples_per_pixel: usize,
+        dimension: usize,
+    ) -> (Scalar, Scalar) {
+        assert!(dimension < DIMENSIONS);
+        match self {
+            Sampler::Random => (rng.gen(), rng.gen()),
+            Sampler::Stratified => {
+                let n = (samples_per_pixel as f64).sqrt() as usize;
+                if index >= n * n {
+                    return (rng.gen(), rng.gen());
+                }
+                // Shuffle the strata so that the dimensions are not correlated
+                let seed = hash(pixel_seed, dimension as u64) as u32;
+                let stratum = permute(index as u32, (n * n) as u32, seed) as usize;
+                let x = (stratum % n) as Scalar;
+                let y = (stratum / n) as Scalar;
+                let (jx, jy): (Scalar, Scalar) = (rng.gen(), rng.gen());
+                (
+                    to_unit((x + jx) / n as Scalar),
+                    to_unit((y + jy) / n as Scalar),
+                )
+            }
+            Sampler::Halton => {
+                // Cranley-Patterson rotation, different for each pixel
+                let h = hash(pixel_seed, dimension as u64);
+                let [bx, by] = HALTON_BASES[dimension];
+                let x = radical_inverse(bx, index as u64) + unit_from_bits(h as u32);
+                let y = radical_inverse(by, index as u64) + unit_from_bits((h >> 32) as u32);
+                (to_unit(x.fract()), to_unit(y.fract()))
+            }
+            Sampler::Sobol => {
+                // Random digit scrambling, different for each pixel
+                let h = hash(pixel_seed, dimension as u64);
+                let x = sobol(index as u32, 2 * dimension) ^ (h as u32);
+                let y = sobol(index as u32, 2 * dimension + 1) ^ ((h >> 32) as u32);
+                (unit_from_bits(x), unit_from_bits(y))
+            }
+        }
+    }
+}
+
+/// Clamps a value computed in floating point just below 1.0
+fn to_unit(v: Scalar) -> Scalar {
+    v.min(1.0 - Scalar::EPSILON)
+}
+
+/// Maps the bits of `v` to [0, 1)
+fn unit_from_bits(v: u32) -> Scalar {
+    // keep 24 bits so that the result is exactly representable
+    (v >> 8) as Scalar / (1u32 << 24) as Scalar
+}
+
+/// Permutation of [0, l) selected by `p`, from Kensler's "Correlated
+/// Multi-Jittered Sampling"
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+fn radical_inverse(base: u32, mut index: u64) -> Scalar {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_n = 1.0;
+    let mut reversed: u64 = 0;
+    while index > 0 {
+        let next = index / base as u64;
+        let digit = index - next * base as u64;
+        reversed = reversed * base as u64 + digit;
+        inv_base_n *= inv_base;
+        index = next;
+    }
+    (reversed as f64 * inv_base_n) as Scalar
+}
+
+/// Primitive polynomials (degree, coefficients) and initial direction numbers
+/// of the Sobol dimensions after the first one, from Joe and Kuo.
+const SOBOL_PARAMETERS: [(usize, u32, [u32; 3]); 2 * DIMENSIONS - 1] =
+    [(1, 0, [1, 0, 0]), (2, 1, [1, 3, 0]), (3, 1, [1, 3, 1])];
+
+const fn sobol_matrices() -> [[u32; 32]; 2 * DIMENSIONS] {
+    let mut matrices = [[0u32; 32]; 2 * DIMENSIONS];
+    let mut k = 0;
+    // First dimension is the van der Corput sequence
+    while k < 32 {
+        matrices[0][k] = 1 << (31 - k);
+        k += 1;
+    }
+    let mut d = 1;
+    while d < 2 * DIMENSIONS {
+        let (s, a, m) = SOBOL_PARAMETERS[d - 1];
+        let mut k = 0;
+        while k < 32 {
+            matrices[d][k] = if k < s {
+                m[k] << (31 - k)
+            } else {
+                let mut v = matrices[d][k - s] ^ (matrices[d][k - s] >> s);
+                let mut l = 1;
+                while l < s {
+                    if (a >> (s - 1 - l)) & 1 == 1 {
+                        v ^= matrices[d][k - l];
+                    }
+                    l += 1;
+                }
+                v
+            };
+            k += 1;
+        }
+        d += 1;
+    }
+    matrices
+}
+
+const SOBOL_MATRICES: [[u32; 32]; 2 * DIMENSIONS] = sobol_matrices();
+
+fn sobol(mut index: u32, dimension: usize) -> u32 {
+    let mut v = 0;
+    let mut k = 0;
+    while index != 0 {
+        if index & 1 == 1 {
+            v ^= SOBOL_MATRICES[dimension][k];
+        }
+        index >>= 1;
+        k += 1;
+    }
+    v
+}
@@ -0,0 +1,201 @@
+//! STL loader, ASCII and binary
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::material::*;
+use crate::math::*;
+use crate::mesh::Mesh;
+
+/// Size of the header of binary files, followed by the number of triangles
+const HEADER_SIZE: usize = 80;
+/// Size of a triangle in binary files: normal, vertices and attributes
+const TRIANGLE_SIZE: usize = 50;
+
+/// Builds an indexed mesh out of a triangle soup, merging identical vertices
+#[derive(Default)]
+struct Builder {
+    vertices: Vec<Point>,
+    indices: Vec<[u32; 3]>,
+    index: HashMap<[u32; 3], u32>,
+}
+
+impl Builder {
+    fn vertex(&mut self, p: Point) -> u32 {
+        let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+        let vertices = &mut self.vertices;
+        *self.index.entry(key).or_insert_with(|| {
+            vertices.push(p);
+            vertices.len() as u32 - 1
+        })
+    }
+
+    fn triangle(&mut self, [a, b, c]: [Point; 3]) {
+        let triangle = [self.vertex(a), self.vertex(b), self.vertex(c)];
+        self.indices.push(triangle);
+    }
+}
+
+/// Binary files start with an arbitrary header, that can begin with "solid"
+/// too: rely on their size instead
+fn is_binary(content: &[u8]) -> bool {
+    let Some(count) = content.get(HEADER_SIZE..HEADER_SIZE + 4) else {
+        return false;
+    };
+    let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+    content.len() == HEADER_SIZE + 4 + count * TRIANGLE_SIZE
+}
+
+fn load_binary(content: &[u8], builder: &mut Builder) {
+    let point = |bytes: &[u8]| {
+        let f = |i: usize| f32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        Point::new(f(0), f(1), f(2))
+    };
+    // The facet normals are ignored: they are often missing, and the
+    // geometric normals are just as good
+    for triangle in content[HEADER_SIZE + 4..].chunks_exact(TRIANGLE_SIZE) {
+        builder.triangle([
+            point(&triangle[12..24]),
+            point(&triangle[24..36]),
+            point(&triangle[36..48]),
+        ]);
+    }
+}
+
+fn ascii_line(line: &str, corners: &mut Vec<Point>, builder: &mut Builder) -> Result<()> {
+    let mut tokens = line.split_whitespace();
+    match tokens.next() {
+        Some("vertex") => {
+            let v = tokens
+                .map(|t| t.parse().with_context(|| format!("Invalid number '{}'", t)))
+                .collect::<Result<Vec<Scalar>>>()?;
+            let [x, y, z] = v[..] else {
+                bail!("Expected 3 coordinates, got {}", v.len());
+            };
+            corners.push(Point::new(x, y, z));
+        }
+        Some("endloop") => {
+            if corners.len() < 3 {
+                bail!("Facet with {} vertices", corners.len());
+            }
+            // Polygons are triangulated as a fan
+            for i in 1..corners.len() - 1 {
+                builder.triangle([corners[0], corners[i], corners[i + 1]]);
+            }
+            corners.clear();
+        }
+        // solid, facet normal, outer loop, endfacet, endsolid
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Loads a STL file, with the given material
+pub fn load(path: &Path, material: Option<&MaterialKind>) -> Result<Mesh> {
+    let material = material
+        .with_context(|| format!("{}: STL meshes need a material", path.display()))?
+        .clone();
+    let content = fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+    let mut builder = Builder::default();
+    if is_binary(&content) {
+        load_binary(&content, &mut builder);
+    } else {
+        let content = String::from_utf8_lossy(&content);
+        let mut corners = Vec::new();
+        for (line_nr, line) in content.lines().enumerate() {
+            ascii_line(line, &mut corners, &mut builder)
+                .with_context(|| format!("{}:{}", path.display(), line_nr + 1))?;
+        }
+    }
+
+    Mesh::new(builder.vertices, builder.indices, None, material)
+        .with_context(|| format!("Invalid mesh '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIANGLES: [[[f32; 3]; 3]; 2] = [
+        [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+        [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+    ];
+
+    fn binary(header: &[u8]) -> Vec<u8> {
+        let mut content = header.to_vec();
+        content.resize(HEADER_SIZE, b' ');
+        content.extend((TRIANGLES.len() as u32).to_le_bytes());
+        for triangle in TRIANGLES {
+            content.extend([0u8; 12]);
+            for value in triangle.iter().flatten() {
+                content.extend(value.to_le_bytes());
+            }
+            content.extend([0u8; 2]);
+        }
+        content
+    }
+
+    fn ascii() -> String {
+        let mut content = String::from("solid square\n");
+        for triangle in TRIANGLES {
+            content += "  facet normal 0 0 1\n    outer loop\n";
+            for [x, y, z] in triangle {
+                content += &format!("      vertex {} {} {}\n", x, y, z);
+            }
+            content += "    endloop\n  endfacet\n";
+        }
+        content + "endsolid square\n"
+    }
+
+    fn check(builder: &Builder) {
+        assert_eq!(builder.vertices.len(), 4);
+        assert_eq!(builder.indices, [[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(builder.vertices[3], Point::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn binary_files() {
+        for header in [&b"binary square"[..], b"solid square", b""] {
+            let content = binary(header);
+            assert!(is_binary(&content));
+            let mut builder = Builder::default();
+            load_binary(&content, &mut builder);
+            check(&builder);
+        }
+        // Trailing garbage, or a wrong triangle count
+        let mut content = binary(b"");
+        content.push(0);
+        assert!(!is_binary(&content));
+        assert!(!is_binary(&binary(b"")[..HEADER_SIZE + 2]));
+    }
+
+    #[test]
+    fn ascii_files() {
+        let content = ascii();
+        assert!(!is_binary(content.as_bytes()));
+        let mut builder = Builder::default();
+        let mut corners = Vec::new();
+        for line in content.lines() {
+            ascii_line(line, &mut corners, &mut builder).unwrap();
+        }
+        check(&builder);
+
+        // Quads are triangulated
+        let mut builder = Builder::default();
+        let quad = "outer loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 1 1 0\nvertex 0 1 0\nendloop";
+        for line in quad.lines() {
+            ascii_line(line, &mut corners, &mut builder).unwrap();
+        }
+        check(&builder);
+
+        assert!(ascii_line("vertex 0 1", &mut corners, &mut builder).is_err());
+        assert!(ascii_line("vertex 0 1 x", &mut corners, &mut builder).is_err());
+        corners.clear();
+        ascii_line("vertex 0 1 0", &mut corners, &mut builder).unwrap();
+        assert!(ascii_line("endloop", &mut corners, &mut builder).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{bail, Result};
+use nalgebra::{Matrix3, Matrix4, Quaternion, Rotation3, UnitQuaternion};
+use parry3d::bounding_volume::Aabb;
+use parry3d::query::{Ray, RayIntersection};
+
+use crate::json;
+use crate::material::*;
+use crate::math::*;
+use crate::world::{Entity, SharedEntity};
+
+/// Affine transformation described by `transform`: scale, then rotate, then
+/// translate
+pub fn matrix(transform: &json::Transform) -> Result<Matrix4<Scalar>> {
+    let translation = transform
+        .translation
+        .as_ref()
+        .map_or(Ok(Vector::zeros()), |t| vec_to_vector(t))?;
+    let rotation = match (&transform.rotation, &transform.quaternion) {
+        (Some(_), Some(_)) => bail!("Transform with both a rotation and a quaternion"),
+        (Some(angles), None) => {
+            let angles = vec_to_vector(angles)?.map(|a| a.to_radians());
+            Rotation3::from_euler_angles(angles.x, angles.y, angles.z).to_homogeneous()
+        }
+        (None, Some(q)) => {
+            let [x, y, z, w] = q[..] else {
+                bail!("Quaternions need 4 components (x, y, z, w)");
+            };
+            UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)).to_homogeneous()
+        }
+        (None, None) => Matrix4::identity(),
+    };
+    let scale = transform
+        .scale
+        .as_ref()
+        .map_or(Ok(Vector::repeat(1.0)), |s| vec_to_vector(s))?;
+    Ok(Matrix4::new_translation(&translation) * rotation * Matrix4::new_nonuniform_scaling(&scale))
+}
+
+/// An entity moved by an affine transformation. The entity is shared, so that
+/// several instances of the same geometry don't duplicate it.
+pub struct Transformed {
+    entity: SharedEntity,
+    /// Object to world space
+    transform: Matrix4<Scalar>,
+    /// World to object space
+    inverse: Matrix4<Scalar>,
+    /// Object to world space, for normals
+    normal_matrix: Matrix3<Scalar>,
+    /// Factor applied to the areas, only known for the transformations that
+    /// preserve angles (uniform scale)
+    area_scale: Option<Scalar>,
+    /// Replaces the material of the entity
+    material: Option<MaterialKind>,
+}
+
+impl Transformed {
+    pub fn new(
+        entity: SharedEntity,
+        transform: Matrix4<Scalar>,
+        material: Option<MaterialKind>,
+    ) -> Result<Self> {
+        let Some(inverse) = transform.try_inverse() else {
+            bail!("Transform can't be inverted");
+        };
+        let normal_matrix = inverse.fixed_view::<3, 3>(0, 0).transpose();
+        let linear = transform.fixed_view::<3, 3>(0, 0);
+        let gram = linear.transpose() * linear;
+        let scale2 = gram.trace() / 3.0;
+        let area_scale =
+            ((gram - Matrix3::identity() * scale2).abs().max() <= 1e-4 * scale2).then_some(scale2);
+        Ok(Transformed {
+            entity,
+            transform,
+            inverse,
+            normal_matrix,
+            area_scale,
+            material,
+        })
+    }
+
+    /// The ray in object space. Its direction is not normalized, so that
+    /// the times of impact are the same in both spaces
+    fn local_ray(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.inverse.transform_point(&ray.origin),
+            self.inverse.transform_vector(&ray.dir),
+        )
+    }
+}
+
+impl Entity for Transformed {
+    fn material(&self) -> &MaterialKind {
+        self.material
+            .as_ref()
+            .unwrap_or_else(|| self.entity.material())
+    }
+
+    fn aabb(&self) -> Aabb {
+        let local = self.entity.aabb();
+        let corners = local.vertices();
+        if !corners.iter().all(|c| c.iter().all(|v| v.is_finite())) {
+            let infinity = Point::new(Scalar::INFINITY, Scalar::INFINITY, Scalar::INFINITY);
+            return Aabb::new(-infinity, infinity);
+        }
+        let mut aabb = Aabb::new_invalid();
+        for corner in &corners {
+            aabb.take_point(self.transform.transform_point(corner));
+        }
+        aabb
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        let mut intersection = self.entity.hit(&self.local_ray(ray), t_min, t_max)?;
+        intersection.normal = (self.normal_matrix * intersection.normal).normalize();
+        Some(intersection)
+    }
+
+    fn hits(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Vec<RayIntersection> {
+        let mut hits = self.entity.hits(&self.local_ray(ray), t_min, t_max);
+        for intersection in &mut hits {
+            intersection.normal = (self.normal_matrix * intersection.normal).normalize();
+        }
+        hits
+    }
+
+    fn color(&self, ray: &Ray, intersection: &RayIntersection) -> Option<Color> {
+        self.entity.color(&self.local_ray(ray), intersection)
+    }
+
+    fn uv_at(&self, ray: &Ray, intersection: &RayIntersection) -> Option<[Scalar; 2]> {
+        self.entity.uv_at(&self.local_ray(ray), intersection)
+    }
+
+    fn geometric_normal(&self, ray: &Ray, intersection: &RayIntersection) -> Vector {
+        // Back to the object space normal, which the normal matrix maps
+        let mut local = *intersection;
+        let linear = self.transform.fixed_view::<3, 3>(0, 0);
+        local.normal = (linear.transpose() * intersection.normal).normalize();
+        let normal = self.entity.geometric_normal(&self.local_ray(ray), &local);
+        (self.normal_matrix * normal).normalize()
+    }
+
+    fn one_sided(&self) -> bool {
+        self.entity.one_sided()
+    }
+
+    fn area(&self) -> Option<Scalar> {
+        Some(self.entity.area()? * self.area_scale?)
+    }
+
+    fn sample(&self, u: (Scalar, Scalar)) -> Option<(Point, Vector)> {
+        self.area_scale?;
+        let (point, normal) = self.entity.sample(u)?;
+        Some((
+            self.transform.transform_point(&point),
+            (self.normal_matrix * normal).normalize(),
+        ))
+    }
+}
@@ -1,37 +1,125 @@
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 
+use anyhow::{bail, Context, Result};
+
+use nalgebra::Matrix4;
+use parry3d::bounding_volume::{Aabb, BoundingVolume};
 use parry3d::query::{Ray, RayCast, RayIntersection};
-use parry3d::shape::Ball;
+use parry3d::shape::{Ball, Capsule, Cone, Cuboid, Cylinder};
 
+use crate::background::Background;
+use crate::bvh::Bvh;
+use crate::csg::{self, Csg};
 use crate::material::*;
 use crate::math::*;
+use crate::mesh::{Mesh, Triangle};
+use crate::primitive::{Convex, Disk, Plane, Quad};
+use crate::transform::{self, Transformed};
+use crate::{obj, ply, stl};
 
 use crate::json;
+use crate::light::Light;
 
 pub trait Entity {
-    fn hit(&self, ray: &Ray) -> Option<RayIntersection>;
+    /// Nearest intersection with a time of impact in [`t_min`, `t_max`]
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection>;
+
+    /// All the intersections in [`t_min`, `t_max`], sorted by time of impact.
+    /// Whether the ray enters or leaves the entity follows from the outward
+    /// normal. By default, the ray is cast again after each hit, `t_min`
+    /// further, or a rounding error further if `t_min` is zero.
+    fn hits(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Vec<RayIntersection> {
+        let mut hits = Vec::new();
+        let mut t = t_min;
+        while let Some(intersection) = self.hit(ray, t, t_max) {
+            if hits.len() == MAX_HITS {
+                break;
+            }
+            t = intersection.toi + t_min.max(Scalar::EPSILON * intersection.toi.max(1.0));
+            hits.push(intersection);
+        }
+        hits
+    }
 
     fn material(&self) -> &MaterialKind;
+
+    /// World space bounding box. Unbounded entities return an infinite box.
+    fn aabb(&self) -> Aabb;
+
+    /// Color of the surface at `intersection`, replacing the albedo of the
+    /// material (e.g. vertex colors). None by default
+    fn color(&self, _ray: &Ray, _intersection: &RayIntersection) -> Option<Color> {
+        None
+    }
+
+    /// Texture coordinates of the surface at `intersection`, for the
+    /// entities that have some. None by default
+    fn uv_at(&self, _ray: &Ray, _intersection: &RayIntersection) -> Option<[Scalar; 2]> {
+        None
+    }
+
+    /// Normal of the actual surface at `intersection`, outward, without the
+    /// smooth shading of the vertex normals: the one `sample` returns. The
+    /// normal of the intersection by default
+    fn geometric_normal(&self, _ray: &Ray, intersection: &RayIntersection) -> Vector {
+        intersection.normal
+    }
+
+    /// Whether only the front face, the side of the outward normal, emits
+    /// light. False by default
+    fn one_sided(&self) -> bool {
+        false
+    }
+
+    /// Surface area, for the entities that `sample` supports. None by default
+    fn area(&self) -> Option<Scalar> {
+        None
+    }
+
+    /// Point uniformly distributed over the surface, and the outward normal
+    /// there, from two uniform numbers in [0, 1). Lets emissive entities be
+    /// sampled as lights.
+    fn sample(&self, _u: (Scalar, Scalar)) -> Option<(Point, Vector)> {
+        None
+    }
 }
 
-// TODO:
-// - sphere is currently solid only
-// - MAX_TOI is not configurable
-const MAX_TOI: f32 = 1000.0;
+/// Entities can be shared, e.g. between instances of the same geometry
+pub type SharedEntity = Arc<dyn Entity + Sync + Send>;
+
+/// Rays start on a surface: ignore the hits too close to their origin, which
+/// are due to floating point errors (shadow acne). Scenes can override it,
+/// e.g. with a larger one for scenes kilometers wide.
+pub const DEFAULT_T_MIN: Scalar = 0.001;
+/// Hits further than this are ignored. Unlimited by default
+pub const DEFAULT_T_MAX: Scalar = Scalar::MAX;
+/// Maximum number of intersections reported by `Entity::hits`
+const MAX_HITS: usize = 256;
 
+/// Intersections reported by an `Entity` carry the outward normal of the
+/// surface, whatever the side the ray comes from: materials are in charge of
+/// flipping it when needed.
 pub struct Sphere {
     ball: Ball,
     isometry: Isometry,
+    /// -1.0 for a negative radius, which turns the sphere inside out
+    /// (e.g. the inner surface of a hollow glass sphere)
+    orientation: Scalar,
     material: MaterialKind,
 }
 
 impl Sphere {
     pub fn new(center: Point, radius: Scalar, material: MaterialKind) -> Self {
-        let ball = Ball::new(radius);
+        let ball = Ball::new(radius.abs());
         let isometry: Isometry = center.into();
+        let orientation = if radius < 0.0 { -1.0 } else { 1.0 };
         Sphere {
             ball,
             isometry,
+            orientation,
             material,
         }
     }
@@ -42,73 +130,768 @@ impl Entity for Sphere {
         &self.material
     }
 
-    fn hit(&self, ray: &Ray) -> Option<RayIntersection> {
-        let result = self
-            .ball
-            .cast_ray_and_get_normal(&self.isometry, ray, MAX_TOI, true);
-        result.filter(|intersection| intersection.toi.abs() > f32::EPSILON)
+    fn aabb(&self) -> Aabb {
+        self.ball.aabb(&self.isometry)
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Scalar, t_max: Scalar) -> Option<RayIntersection> {
+        // Start the ray a bit further, instead of filtering out the close
+        // hits, so that a ray leaving the surface still sees the hits behind
+        let shifted = Ray::new(ray.point_at(t_min), ray.dir);
+        // Not solid: rays starting inside the sphere must see its boundary
+        let result =
+            self.ball
+                .cast_ray_and_get_normal(&self.isometry, &shifted, t_max - t_min, false);
+        result.map(|mut intersection| {
+            intersection.toi += t_min;
+            let center: Point = self.isometry.translation.vector.into();
+            let outward = (ray.point_at(intersection.toi) - center).normalize();
+            intersection.normal = self.orientation * outward;
+            intersection
+        })
+    }
+
+    fn area(&self) -> Option<Scalar> {
+        Some(4.0 * std::f32::consts::PI * self.ball.radius * self.ball.radius)
+    }
+
+    fn sample(&self, u: (Scalar, Scalar)) -> Option<(Point, Vector)> {
+        let outward = uniform_sample_sphere(u.0, u.1);
+        let center: Point = self.isometry.translation.vector.into();
+        Some((
+            center + self.ball.radius * outward,
+            self.orientation * outward,
+        ))
     }
 }
 
+/// Entities with a finite bounding box, and their hierarchy
+struct Accelerator {
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+    bvh: Bvh,
+    /// Emissive entities that can be sampled as lights
+    emitters: Vec<usize>,
+}
+
+/// Closest intersection along a ray
+pub struct Hit<'a> {
+    pub intersection: RayIntersection,
+    /// Material at the intersection, with the color of the entity
+    pub material: Cow<'a, MaterialKind>,
+    /// Texture coordinates at the intersection, if the entity has some
+    pub uv: Option<[Scalar; 2]>,
+    entity: usize,
+}
+
 pub struct World {
-    entities: Vec<Arc<dyn Entity + Sync + Send>>,
+    entities: Vec<SharedEntity>,
+    background: Background,
+    lights: Vec<Light>,
+    /// Range of the times of impact of the hits
+    t_min: Scalar,
+    t_max: Scalar,
+    /// Built on the first query, once all the entities are known
+    accelerator: OnceLock<Accelerator>,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl World {
     pub fn new() -> Self {
         World {
             entities: Vec::new(),
+            background: Background::default(),
+            lights: Vec::new(),
+            t_min: DEFAULT_T_MIN,
+            t_max: DEFAULT_T_MAX,
+            accelerator: OnceLock::new(),
         }
     }
 
-    pub fn add<E>(&mut self, e: Arc<E>)
-    where
-        E: Entity + Send + Sync + 'static,
-    {
-        self.entities.push(e)
+    pub fn background(&self) -> &Background {
+        &self.background
     }
 
-    pub fn hit(&self, ray: &Ray) -> Option<(RayIntersection, &MaterialKind)> {
-        let mut ret = None;
-        let mut closest_toi = MAX_TOI;
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background
+    }
 
-        for entity in &self.entities {
-            if let Some(intersection) = entity.hit(ray) {
-                if intersection.toi < closest_toi {
-                    closest_toi = intersection.toi;
-                    ret = Some((intersection, entity.material()));
-                }
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light)
+    }
+
+    /// Only the hits with a time of impact in [`t_min`, `t_max`] count
+    pub fn set_t_range(&mut self, t_min: Scalar, t_max: Scalar) {
+        self.t_min = t_min;
+        self.t_max = t_max;
+    }
+
+    pub fn add(&mut self, e: SharedEntity) {
+        self.entities.push(e);
+        self.accelerator = OnceLock::new();
+    }
+
+    fn accelerator(&self) -> &Accelerator {
+        self.accelerator.get_or_init(|| {
+            let (bounded, unbounded): (Vec<_>, Vec<_>) = (0..self.entities.len()).partition(|i| {
+                let aabb = self.entities[*i].aabb();
+                aabb.mins
+                    .iter()
+                    .chain(aabb.maxs.iter())
+                    .all(|v| v.is_finite())
+            });
+            let aabbs: Vec<_> = bounded.iter().map(|i| self.entities[*i].aabb()).collect();
+            let emitters = (0..self.entities.len())
+                .filter(|i| {
+                    let entity = &self.entities[*i];
+                    entity.material().is_emissive() && entity.area().is_some_and(|a| a > 0.0)
+                })
+                .collect();
+            Accelerator {
+                bvh: Bvh::new(&aabbs),
+                bounded,
+                unbounded,
+                emitters,
+            }
+        })
+    }
+
+    /// Emissive entities that can be sampled as lights
+    pub(crate) fn emitters(&self) -> impl Iterator<Item = &SharedEntity> {
+        self.accelerator()
+            .emitters
+            .iter()
+            .map(|i| &self.entities[*i])
+    }
+
+    /// Bounds of the bounded entities, if any
+    pub(crate) fn bounds(&self) -> Option<Aabb> {
+        self.merged_bounds(|_| true)
+    }
+
+    /// Bounds of the bounded entities with a specular material, if any
+    pub(crate) fn specular_bounds(&self) -> Option<Aabb> {
+        self.merged_bounds(|entity| entity.material().flags().intersects(BsdfFlags::SPECULAR))
+    }
+
+    /// Whether some entities are unbounded, outside of `bounds`
+    pub(crate) fn is_unbounded(&self) -> bool {
+        !self.accelerator().unbounded.is_empty()
+    }
+
+    /// Whether the entity hit is a bounded one, within `bounds`
+    pub(crate) fn is_bounded(&self, hit: &Hit) -> bool {
+        self.accelerator()
+            .bounded
+            .binary_search(&hit.entity)
+            .is_ok()
+    }
+
+    fn merged_bounds(&self, filter: impl Fn(&SharedEntity) -> bool) -> Option<Aabb> {
+        self.accelerator()
+            .bounded
+            .iter()
+            .map(|i| &self.entities[*i])
+            .filter(|entity| filter(entity))
+            .map(|entity| entity.aabb())
+            .reduce(|acc, aabb| acc.merged(&aabb))
+    }
+
+    pub fn hit(&self, ray: &Ray) -> Option<Hit<'_>> {
+        let accelerator = self.accelerator();
+
+        let mut ret = accelerator
+            .bvh
+            .traverse(ray, self.t_max, |i, max_toi| {
+                let index = accelerator.bounded[i];
+                self.entities[index]
+                    .hit(ray, self.t_min, max_toi)
+                    .map(|intersection| (intersection.toi, (intersection, index)))
+            })
+            .map(|(_, hit)| hit);
+        let mut closest_toi = ret.as_ref().map_or(self.t_max, |(i, _)| i.toi);
+
+        for i in &accelerator.unbounded {
+            if let Some(intersection) = self.entities[*i].hit(ray, self.t_min, closest_toi) {
+                closest_toi = intersection.toi;
+                ret = Some((intersection, *i));
+            }
+        }
+
+        let (intersection, index) = ret?;
+        let entity = &self.entities[index];
+        let mut material = match entity.color(ray, &intersection) {
+            Some(color) => Cow::Owned(entity.material().with_albedo(color)),
+            None => Cow::Borrowed(entity.material()),
+        };
+        let (_, front_face) = face_forward(&intersection.normal, &ray.dir);
+        if entity.one_sided() && !front_face {
+            material = Cow::Owned(material.without_emission());
+        }
+        let uv = entity.uv_at(ray, &intersection);
+        Some(Hit {
+            intersection,
+            material,
+            uv,
+            entity: index,
+        })
+    }
+
+    /// Picks a point on one of the emissive entities, uniformly by area, as
+    /// seen from `point`. Returns the unit direction towards it, the radiance
+    /// arriving from it and the solid angle density of that direction. None
+    /// if there is no emitter, or if the point is hidden.
+    pub fn sample_emitter(
+        &self,
+        point: &Point,
+        u_emitter: Scalar,
+        u: (Scalar, Scalar),
+    ) -> Option<(Vector, Color, Scalar)> {
+        let emitters = &self.accelerator().emitters;
+        if emitters.is_empty() {
+            return None;
+        }
+        let index =
+            emitters[((u_emitter * emitters.len() as Scalar) as usize).min(emitters.len() - 1)];
+        let (target, normal) = self.entities[index].sample(u)?;
+        let to_target = target - point;
+        let distance = to_target.norm();
+        if distance == 0.0 {
+            return None;
+        }
+        let wi = to_target / distance;
+        let ray = Ray::new(*point, wi);
+        let hit = self.hit(&ray)?;
+        if hit.entity != index || hit.intersection.toi < distance * (1.0 - 1e-3) {
+            return None;
+        }
+        let radiance = hit.material.emitted(&ray, &hit.intersection);
+        let pdf = self.emitter_density(index, distance, normal.dot(&wi).abs());
+        (pdf > 0.0).then_some((wi, radiance, pdf))
+    }
+
+    /// Solid angle density of `sample_emitter` picking the direction of `ray`,
+    /// which leads to `hit`. Zero if the entity hit isn't sampled as a light
+    pub fn emitter_pdf(&self, ray: &Ray, hit: &Hit) -> Scalar {
+        if self
+            .accelerator()
+            .emitters
+            .binary_search(&hit.entity)
+            .is_err()
+        {
+            return 0.0;
+        }
+        let length = ray.dir.norm();
+        let distance = hit.intersection.toi * length;
+        // The normal `sample_emitter` weighs the samples with, not the
+        // shading one
+        let normal = self.entities[hit.entity].geometric_normal(ray, &hit.intersection);
+        let cos_theta = normal.dot(&ray.dir).abs() / length;
+        self.emitter_density(hit.entity, distance, cos_theta)
+    }
+
+    /// Converts the density of sampling the emitter `index` uniformly, per
+    /// unit area, to a density per unit solid angle
+    fn emitter_density(&self, index: usize, distance: Scalar, cos_theta: Scalar) -> Scalar {
+        let area = self.entities[index].area().unwrap_or(0.0);
+        let count = self.accelerator().emitters.len() as Scalar;
+        if cos_theta == 0.0 || area == 0.0 {
+            return 0.0;
+        }
+        distance * distance / (cos_theta * area * count)
+    }
+}
+
+fn material(materials: &HashMap<String, MaterialKind>, name: &str) -> Result<MaterialKind> {
+    materials
+        .get(name)
+        .cloned()
+        .with_context(|| format!("Unknown material '{}'", name))
+}
+
+fn vertex_normals(normals: Option<Vec<Vec<f32>>>) -> Result<Option<Vec<Vector>>> {
+    normals
+        .map(|ns| ns.iter().map(|n| vec_to_vector(n)).collect())
+        .transpose()
+}
+
+/// Loads a mesh file, picking the format from its extension
+fn load_mesh(path: &Path, material: Option<&MaterialKind>) -> Result<Vec<Mesh>> {
+    let extension = path.extension().and_then(|e| e.to_str());
+    match extension.map(|e| e.to_lowercase()).as_deref() {
+        Some("obj") => obj::load(path, material),
+        Some("ply") => Ok(vec![ply::load(path, material)?]),
+        Some("stl") => Ok(vec![stl::load(path, material)?]),
+        _ => bail!("Unsupported mesh format '{}'", path.display()),
+    }
+}
+
+/// Name, transform and untransformed geometry of a shape
+type Geometry = (Option<String>, Option<json::Transform>, Vec<SharedEntity>);
+
+fn geometry(shape: json::Shape, materials: &HashMap<String, MaterialKind>) -> Result<Geometry> {
+    match shape {
+        json::Shape::Sphere(json::Sphere {
+            center,
+            radius,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let sphere = Sphere::new(vec_to_point(&center)?, radius, mat);
+            Ok((shape_name, transform, vec![Arc::new(sphere)]))
+        }
+        json::Shape::Triangle(json::Triangle {
+            vertices,
+            normals,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let vertices: Vec<Point> = vertices
+                .iter()
+                .map(|v| vec_to_point(v))
+                .collect::<Result<_>>()?;
+            let normals = vertex_normals(normals)?;
+            let (Ok(vertices), Ok(normals)) = (
+                <[Point; 3]>::try_from(vertices),
+                normals.map(<[Vector; 3]>::try_from).transpose(),
+            ) else {
+                bail!("A triangle needs 3 vertices, and 3 normals if any");
+            };
+            let triangle = Triangle::new(vertices, normals, mat);
+            Ok((shape_name, transform, vec![Arc::new(triangle)]))
+        }
+        json::Shape::TriMesh(json::TriMesh {
+            vertices,
+            indices,
+            normals,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let vertices = vertices
+                .iter()
+                .map(|v| vec_to_point(v))
+                .collect::<Result<_>>()?;
+            let mesh = Mesh::new(vertices, indices, vertex_normals(normals)?, mat)?;
+            Ok((shape_name, transform, vec![Arc::new(mesh)]))
+        }
+        json::Shape::Cuboid(json::Cuboid {
+            center,
+            half_extents,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let cuboid = Cuboid::new(vec_to_vector(&half_extents)?);
+            let entity = Convex::new(cuboid, vec_to_point(&center)?.into(), mat);
+            Ok((shape_name, transform, vec![Arc::new(entity)]))
+        }
+        json::Shape::Cylinder(json::Cylinder {
+            center,
+            radius,
+            height,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let cylinder = Cylinder::new(height / 2.0, radius);
+            let entity = Convex::new(cylinder, vec_to_point(&center)?.into(), mat);
+            Ok((shape_name, transform, vec![Arc::new(entity)]))
+        }
+        json::Shape::Cone(json::Cone {
+            center,
+            radius,
+            height,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let cone = Cone::new(height / 2.0, radius);
+            let entity = Convex::new(cone, vec_to_point(&center)?.into(), mat);
+            Ok((shape_name, transform, vec![Arc::new(entity)]))
+        }
+        json::Shape::Capsule(json::Capsule {
+            a,
+            b,
+            radius,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let capsule = Capsule::new(vec_to_point(&a)?, vec_to_point(&b)?, radius);
+            let entity = Convex::new(capsule, Isometry::identity(), mat);
+            Ok((shape_name, transform, vec![Arc::new(entity)]))
+        }
+        json::Shape::Plane(json::Plane {
+            point,
+            normal,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let plane = Plane::new(vec_to_point(&point)?, vec_to_vector(&normal)?, mat);
+            Ok((shape_name, transform, vec![Arc::new(plane)]))
+        }
+        json::Shape::Quad(json::Quad {
+            corner,
+            u,
+            v,
+            one_sided,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let (u, v) = (vec_to_vector(&u)?, vec_to_vector(&v)?);
+            let quad = Quad::new(vec_to_point(&corner)?, u, v, one_sided, mat);
+            Ok((shape_name, transform, vec![Arc::new(quad)]))
+        }
+        json::Shape::Disk(json::Disk {
+            center,
+            normal,
+            radius,
+            one_sided,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = material(materials, &name)?;
+            let (center, normal) = (vec_to_point(&center)?, vec_to_vector(&normal)?);
+            let disk = Disk::new(center, normal, radius, one_sided, mat);
+            Ok((shape_name, transform, vec![Arc::new(disk)]))
+        }
+        json::Shape::Mesh(json::MeshFile {
+            path,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            let mat = name.map(|name| material(materials, &name)).transpose()?;
+            let meshes = load_mesh(Path::new(&path), mat.as_ref())?;
+            let entities = meshes
+                .into_iter()
+                .map(|mesh| Arc::new(mesh) as SharedEntity)
+                .collect();
+            Ok((shape_name, transform, entities))
+        }
+        json::Shape::Csg(json::Csg {
+            operation,
+            shapes,
+            material: name,
+            name: shape_name,
+            transform,
+        }) => {
+            if shapes.is_empty() {
+                bail!("CSG without shapes");
+            }
+            let mut children = Vec::new();
+            for shape in shapes {
+                let (_, transform, entities) = geometry(shape, materials)?;
+                // Shapes made of several entities (meshes with several
+                // materials) must count as one
+                let child: SharedEntity = if entities.len() == 1 {
+                    entities.into_iter().next().unwrap()
+                } else {
+                    let mat = entities[0].material().clone();
+                    Arc::new(Csg::new(csg::Operation::Union, entities, mat))
+                };
+                children.push(place(child, transform.as_ref(), None)?);
             }
+            let mat = match name {
+                Some(name) => material(materials, &name)?,
+                None => children[0].material().clone(),
+            };
+            let csg = Csg::new(operation, children, mat);
+            Ok((shape_name, transform, vec![Arc::new(csg)]))
         }
-        ret
+        json::Shape::Instance(_) => bail!("Instances have no geometry of their own"),
     }
 }
 
-fn vec_to_point(vec: Vec<f32>) -> Point {
-    // TODO: check size and report error
-    let x = vec[0];
-    let y = vec[1];
-    let z = vec[2];
-    Point::new(x, y, z)
+/// Moves `entity` by `transform` and replaces its material, if needed
+fn place(
+    entity: SharedEntity,
+    transform: Option<&json::Transform>,
+    material: Option<MaterialKind>,
+) -> Result<SharedEntity> {
+    if transform.is_none() && material.is_none() {
+        return Ok(entity);
+    }
+    let matrix = match transform {
+        Some(transform) => transform::matrix(transform)?,
+        None => Matrix4::identity(),
+    };
+    Ok(Arc::new(Transformed::new(entity, matrix, material)?))
 }
 
-impl From<json::World> for World {
-    fn from(value: json::World) -> Self {
-        let json::World { materials, shapes } = value;
+impl TryFrom<json::World> for World {
+    type Error = anyhow::Error;
+
+    fn try_from(value: json::World) -> Result<Self> {
+        let json::World {
+            background,
+            lights,
+            t_min,
+            t_max,
+            materials,
+            shapes,
+            ..
+        } = value;
         let mut world = World::new();
+        if let Some(background) = background {
+            world.set_background(Background::load(background)?);
+        }
+        let t_min = t_min.unwrap_or(DEFAULT_T_MIN);
+        let t_max = t_max.unwrap_or(DEFAULT_T_MAX);
+        if !(0.0 < t_min && t_min < t_max) {
+            bail!(
+                "Invalid hit range [{}, {}]: t_min must be positive and below t_max",
+                t_min,
+                t_max
+            );
+        }
+        world.set_t_range(t_min, t_max);
+        for light in lights {
+            world.add_light(light);
+        }
+
+        // Instances are added once all the shapes they may refer to are known
+        let mut named: HashMap<String, Vec<SharedEntity>> = HashMap::new();
+        let mut instances = Vec::new();
         for shape in shapes {
-            match shape {
-                json::Shape::Sphere(json::Sphere {
-                    center,
-                    radius,
-                    material,
-                }) => {
-                    let mat = materials.get(&material).unwrap().clone();
-                    let sphere = Sphere::new(vec_to_point(center), radius, mat);
-                    world.add(Arc::new(sphere));
+            if let json::Shape::Instance(instance) = shape {
+                instances.push(instance);
+                continue;
+            }
+            let (name, transform, entities) = geometry(shape, &materials)?;
+            if let Some(name) = name {
+                if named.insert(name.clone(), entities.clone()).is_some() {
+                    bail!("Several shapes are named '{}'", name);
                 }
             }
+            for entity in entities {
+                world.add(place(entity, transform.as_ref(), None)?);
+            }
+        }
+
+        for json::Instance {
+            of,
+            transform,
+            material: name,
+        } in instances
+        {
+            let entities = named
+                .get(&of)
+                .with_context(|| format!("Instance of unknown shape '{}'", of))?;
+            let mat = name.map(|name| material(&materials, &name)).transpose()?;
+            for entity in entities {
+                world.add(place(entity.clone(), transform.as_ref(), mat.clone())?);
+            }
+        }
+        Ok(world)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Rotation3, Translation3};
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// `emitter_pdf` must find the density `sample_emitter` picked its
+    /// directions with, even on smooth shaded meshes, whose shading normals
+    /// differ from the ones of the surface
+    #[test]
+    fn emitter_pdf_matches_sample_emitter() {
+        let light = MaterialKind::DiffuseLight(DiffuseLight::new(Color::new(1.0, 1.0, 1.0)));
+        let vertices = vec![
+            Point::new(-1.0, 2.0, -1.0),
+            Point::new(1.0, 2.0, -1.0),
+            Point::new(1.0, 2.0, 1.0),
+            Point::new(-1.0, 2.0, 1.0),
+        ];
+        let normals = vec![
+            Vector::new(0.5, -1.0, 0.0),
+            Vector::new(-0.5, -1.0, 0.3),
+            Vector::new(0.0, -1.0, -0.5),
+            Vector::new(0.4, -1.0, 0.4),
+        ];
+        let mesh = Mesh::new(
+            vertices.clone(),
+            vec![[0, 2, 1], [0, 3, 2]],
+            Some(normals.clone()),
+            light.clone(),
+        )
+        .unwrap();
+        let mesh: SharedEntity = Arc::new(mesh);
+        let triangle = Triangle::new(
+            [vertices[0], vertices[2], vertices[1]].map(|v| v + Vector::new(3.0, 0.0, 0.0)),
+            Some([normals[0], normals[2], normals[1]]),
+            light,
+        );
+        let transform = Translation3::new(-3.0, 0.5, 0.0).to_homogeneous()
+            * Rotation3::from_euler_angles(0.3, 0.2, 0.1).to_homogeneous();
+        let instance = Transformed::new(mesh.clone(), transform, None).unwrap();
+
+        let mut world = World::new();
+        world.add(mesh);
+        world.add(Arc::new(triangle));
+        world.add(Arc::new(instance));
+
+        let mut rng = Prng::seed_from_u64(0);
+        let point = Point::origin();
+        let mut count = 0;
+        for _ in 0..1000 {
+            let Some((wi, _, pdf)) =
+                world.sample_emitter(&point, rng.gen(), (rng.gen(), rng.gen()))
+            else {
+                continue;
+            };
+            let ray = Ray::new(point, wi);
+            let hit = world.hit(&ray).unwrap();
+            let expected = world.emitter_pdf(&ray, &hit);
+            assert!(
+                (pdf - expected).abs() <= 1e-3 * pdf,
+                "{} != {}",
+                pdf,
+                expected
+            );
+            count += 1;
+        }
+        assert!(count > 500);
+    }
+
+    #[test]
+    fn hits_carry_the_mesh_uvs() {
+        let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let square = Mesh::new(
+            vec![
+                Point::new(0.0, 0.0, 0.0),
+                Point::new(1.0, 0.0, 0.0),
+                Point::new(1.0, 1.0, 0.0),
+                Point::new(0.0, 1.0, 0.0),
+            ],
+            vec![[0, 1, 2], [0, 2, 3]],
+            None,
+            material.clone(),
+        )
+        .unwrap()
+        .with_uvs(vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]])
+        .unwrap();
+        let square: SharedEntity = Arc::new(square);
+        // Same square, twice as large, further along X
+        let transform =
+            Translation3::new(5.0, 0.0, 0.0).to_homogeneous() * Matrix4::new_scaling(2.0);
+        let instance = Transformed::new(square.clone(), transform, None).unwrap();
+
+        let mut world = World::new();
+        world.add(square);
+        world.add(Arc::new(instance));
+        world.add(Arc::new(Sphere::new(
+            Point::new(-5.0, 0.0, 0.0),
+            1.0,
+            material,
+        )));
+
+        let uv = |x: Scalar, y: Scalar| {
+            let ray = Ray::new(Point::new(x, y, 1.0), -Vector::z());
+            world.hit(&ray).unwrap().uv
+        };
+        for (x, y) in [(0.25, 0.75), (0.8, 0.1), (0.5, 0.5)] {
+            let [u, v] = uv(x, y).unwrap();
+            assert!((u - x).abs() < 1e-5 && (v - y).abs() < 1e-5);
+            let [u, v] = uv(5.0 + 2.0 * x, 2.0 * y).unwrap();
+            assert!((u - x).abs() < 1e-5 && (v - y).abs() < 1e-5);
         }
-        world
+        assert_eq!(uv(-5.0, 0.0), None);
+    }
+
+    #[test]
+    fn hits_carry_the_quad_and_disk_uvs() {
+        let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let quad = Quad::new(
+            Point::origin(),
+            Vector::new(2.0, 0.0, 0.0),
+            Vector::new(0.0, 1.0, 0.0),
+            false,
+            material.clone(),
+        );
+        let disk = Disk::new(
+            Point::new(10.0, 0.0, 0.0),
+            Vector::z(),
+            2.0,
+            false,
+            material,
+        );
+        let mut world = World::new();
+        world.add(Arc::new(quad));
+        world.add(Arc::new(disk));
+
+        let uv = |x: Scalar, y: Scalar| {
+            let ray = Ray::new(Point::new(x, y, 1.0), -Vector::z());
+            world.hit(&ray).unwrap().uv.unwrap()
+        };
+        let close =
+            |[u, v]: [Scalar; 2], [a, b]: [Scalar; 2]| (u - a).abs() < 1e-5 && (v - b).abs() < 1e-5;
+        assert!(close(uv(0.0, 0.0), [0.0, 0.0]));
+        assert!(close(uv(2.0, 0.0), [1.0, 0.0]));
+        assert!(close(uv(0.0, 1.0), [0.0, 1.0]));
+        assert!(close(uv(2.0, 1.0), [1.0, 1.0]));
+        assert!(close(uv(1.0, 0.5), [0.5, 0.5]));
+
+        assert!(uv(10.0, 0.0)[0].abs() < 1e-5);
+        let [r, a] = uv(11.0, 0.0);
+        let [s, b] = uv(9.0, 0.0);
+        assert!((r - 0.5).abs() < 1e-5 && (s - 0.5).abs() < 1e-5);
+        // Opposite points are half a turn apart
+        assert!(((a - b).rem_euclid(1.0) - 0.5).abs() < 1e-5);
+        let [r, _] = uv(10.0, 2.0);
+        assert!((r - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn zero_t_min() {
+        let json = r#"{"t_min": 0, "materials": {}, "shapes": []}"#;
+        let world: json::World = serde_json::from_str(json).unwrap();
+        assert!(World::try_from(world).is_err());
+
+        // Casting again from the last hit must not find it again
+        let material = MaterialKind::Lambertian(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let sphere = Sphere::new(Point::origin(), 1.0, material);
+        let ray = Ray::new(Point::new(0.0, 0.0, -5.0), Vector::z());
+        let hits = sphere.hits(&ray, 0.0, Scalar::INFINITY);
+        assert_eq!(hits.len(), 2);
+        assert!((hits[0].toi - 4.0).abs() < 1e-5 && (hits[1].toi - 6.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn short_coordinates_are_reported() {
+        let json = r#"{
+            "materials": {"grey": {"Lambertian": {"albedo": [0.5, 0.5, 0.5]}}},
+            "shapes": [{"sphere": {"center": [0, 1], "radius": 1, "material": "grey"}}]
+        }"#;
+        let world: json::World = serde_json::from_str(json).unwrap();
+        let error = World::try_from(world).err().unwrap();
+        assert!(error.to_string().contains("Expected 3 coordinates"));
     }
 }
@@ -0,0 +1,74 @@
+//! Renders are a function of the scene and the seed only: neither the run
+//! nor the number of threads may change a single byte of the image.
+
+use photonr::camera::{Camera, CameraSettings, Integrator};
+use photonr::json;
+use photonr::world::World;
+
+const SCENE: &str = r#"{
+  "materials": {
+    "ground": { "Lambertian": { "albedo": [0.8, 0.8, 0.0] }},
+    "glass":  { "Dielectric": { "refraction_index": 1.5 }},
+    "metal":  { "Metal": { "albedo": [0.8, 0.6, 0.2], "fuzz": 0.3 }},
+    "light":  { "DiffuseLight": { "emit": [4.0, 4.0, 4.0] }}
+  },
+  "shapes": [
+    { "plane": { "point": [0.0, -0.5, 0.0], "normal": [0.0, 1.0, 0.0], "material": "ground" }},
+    { "sphere": { "center": [-0.6, 0.0, -1.0], "radius": 0.5, "material": "glass" }},
+    { "sphere": { "center": [0.6, 0.0, -1.0], "radius": 0.5, "material": "metal" }},
+    { "sphere": { "center": [0.0, 1.5, -1.0], "radius": 0.3, "material": "light" }}
+  ]
+}"#;
+
+fn world() -> World {
+    let world: json::World = serde_json::from_str(SCENE).unwrap();
+    world.try_into().unwrap()
+}
+
+fn render(settings: &CameraSettings, threads: usize) -> Vec<u8> {
+    let camera = Camera::new(settings);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap()
+        .install(|| camera.render(world(), |_| ()))
+}
+
+fn assert_deterministic(integrator: Integrator) {
+    let settings = CameraSettings {
+        image_width: 24,
+        aspect_ratio: 1.5,
+        samples_per_pixel: 3,
+        max_depth: 5,
+        seed: 7,
+        integrator,
+        photons: 2_000,
+        ..CameraSettings::default()
+    };
+    let reference = render(&settings, 1);
+    assert_eq!(reference.len(), 24 * 16 * 3);
+    assert!(reference.iter().any(|c| *c != 0));
+    assert!(render(&settings, 1) == reference, "two runs differ");
+    assert!(render(&settings, 4) == reference, "thread count matters");
+
+    let other_seed = CameraSettings {
+        seed: 8,
+        ..settings
+    };
+    assert!(render(&other_seed, 1) != reference, "seed is ignored");
+}
+
+#[test]
+fn path_tracing_is_deterministic() {
+    assert_deterministic(Integrator::Path);
+}
+
+#[test]
+fn photon_mapping_is_deterministic() {
+    assert_deterministic(Integrator::Photon);
+}
+
+#[test]
+fn progressive_photon_mapping_is_deterministic() {
+    assert_deterministic(Integrator::Sppm);
+}